@@ -46,8 +46,101 @@ defmodule BamlElixir.Client do
     - `args`: A map of arguments to pass to the function
     - `opts`: A map of options
       - `path`: The path to the BAML source file
-      - `collectors`: A list of collectors to use
-      - `llm_client`: The name of the LLM client to use
+      - `collectors`: A list of collectors to use. Collectors log the requests BAML sends, so
+        calls using "mock" or "elixir" clients or a runtime replaying a cassette can't have
+        any.
+      - `llm_client`: The name of the LLM client to use, or a `"provider/model"` shorthand like
+        `"openai/gpt-4o-mini"`, which reads the provider's API key from the environment
+      - `client_registry`: A map of clients defined at runtime or a `BamlElixir.ClientRegistry`,
+        see "Client registry" below
+      - `client_options`: A map of client names to options merged on top of the client's
+        declared options for this call, e.g. `%{"GPT4" => %{temperature: 0.0}}`. Nested maps
+        like `headers` are merged and `nil` removes an option. Clients of any provider can be
+        overridden. The declared options are read from the BAML source files, so the
+        environment variables they refer to must be set.
+        `retry_policy` replaces the client's retry policy, or removes it when `nil`.
+      - `headers`: A map of extra HTTP headers added to the requests the call sends, to
+        every client it may use except AWS Bedrock ones, e.g. `%{"x-tenant-id" => "acme"}`
+      - `runtime`: A `BamlElixir.Runtime` to use instead of loading the BAML source files
+        from `path`, see `BamlElixir.Runtime.new/2` for client limits
+      - `timeout`: How long to wait for the result in milliseconds, `:infinity` by default.
+        Calls that time out return `{:error, :timeout}` and are cancelled, see "Cancellation"
+        below.
+
+  ## Cancellation
+
+  Calls run on a pool of 64 worker threads, and wait in line when all of them are busy.
+  A call is cancelled when it times out or when the process that made it exits. Cancelled
+  calls that haven't started don't run, and calls with mock, elixir or limited clients, or
+  with a circuit breaker, stop before their next request. A request BAML already sent runs
+  to completion.
+
+  ## Client registry
+
+  Clients can be defined at runtime instead of in the BAML source files:
+
+      %{
+        primary: "MyClient",
+        clients: [
+          %{
+            name: "MyClient",
+            provider: "openai",
+            retry_policy: "Exponential",
+            options: %{model: "gpt-4o-mini", api_key: "sk-...", temperature: 0.2}
+          }
+        ]
+      }
+
+  `primary` is the client used by the function; `llm_client` takes precedence over it when
+  both are given. `retry_policy` names a retry policy defined in the BAML source files or in
+  the registry's `retry_policies`:
+
+      retry_policies: [
+        %{name: "Aggressive", max_retries: 5, strategy: "exponential_backoff",
+          delay_ms: 200, multiplier: 2, max_delay_ms: 10_000},
+        %{name: "Steady", max_retries: 2, strategy: "constant_delay", delay_ms: 500}
+      ]
+
+  Retry policies are loaded with the BAML source files, so calls with a `runtime` must declare
+  them with the runtime's `retry_policies` option instead, see `BamlElixir.Runtime.new/2`.
+
+  `fallback` and `round-robin` clients take a `strategy` list of client names, which may
+  refer to clients from the BAML source files or from the registry, and round-robin clients
+  an optional `start` index:
+
+      %{name: "MyFallback", provider: "fallback", strategy: ["MyClient", "GPT4"]}
+
+  `elixir` clients hand their requests to a process instead of sending them. BAML builds the
+  request for the `api` provider in `options`, which defaults to `openai-generic`, and parses
+  the response the process replies with:
+
+      %{name: "InProcess", provider: "elixir", handler: pid, timeout_ms: 30_000,
+        options: %{api: "openai-generic", model: "gpt-4o-mini"}}
+
+  The process gets `{:baml_elixir_request, reply, request}` messages, where `request` is a
+  map of `client`, `function`, `url`, `method`, `headers`, `body` and `stream`, and answers with
+  `BamlElixir.Runtime.reply(reply, {:ok, %{status: 200, body: body}})` or
+  `BamlElixir.Runtime.reply(reply, {:error, reason})`. Streamed requests can also be answered
+  with any number of `{:chunk, data}` replies of server-sent events followed by `:done`.
+
+  `mock` clients answer with canned responses instead of calling a provider, e.g. for tests
+  that run offline. A response is the text the LLM would reply with, `%{chunks: [text]}` to
+  stream it in chunks, or `%{error: message}` to fail like a provider would:
+
+      %{name: "Fake", provider: "mock", latency_ms: 50,
+        responses: %{"ExtractResume" => ~s({"name": "John Doe"}), "WhichModel" => ["GPT4oMini"]},
+        response: %{error: "Service unavailable"}}
+
+  `responses` is a map of function names to a response or a list of responses served in
+  order, or a list of responses served in order to any function. `response` is served once
+  those are used up. `latency_ms` delays each response, and each chunk of streamed ones.
+  Lists are shared by calls reusing a registry created with `BamlElixir.ClientRegistry.new/1`.
+  Mock clients are retried with their retry policy and used by fallback and round-robin
+  clients like any other client.
+
+  To validate a registry once and reuse it across calls, create it with
+  `BamlElixir.ClientRegistry.new/1`. Invalid registries return
+  `{:error, %{kind: :invalid_client_registry, client: client, option: option, message: message}}`.
 
   ## Returns
     - `{:ok, term()}` on success, where the term is the function's return value
@@ -61,12 +154,26 @@ defmodule BamlElixir.Client do
   def call(function_name, args, opts \\ %{}) do
     {path, collectors, client_registry, tb} = prepare_opts(opts)
     args = to_map(args)
+    ref = make_ref()
 
-    with {:ok, result} <-
-           BamlElixir.Native.call(function_name, args, path, collectors, client_registry, tb) do
+    # The call runs on a worker thread, which sends its result back
+    with {:ok, call} <-
+           start_call(
+             BamlElixir.Native.call(
+               self(),
+               ref,
+               function_name,
+               args,
+               path,
+               collectors,
+               client_registry,
+               tb
+             )
+           ),
+         {:ok, result} <- receive_result(call, ref, opts[:timeout] || :infinity) do
       result =
         if opts[:parse] != false do
-          parse_result(result, opts[:prefix], tb)
+          parse_result(result, opts[:prefix])
         else
           result
         end
@@ -75,6 +182,34 @@ defmodule BamlElixir.Client do
     end
   end
 
+  @doc """
+  Returns the text `{{ ctx.output_format }}` renders to in the prompt of a BAML function,
+  i.e. how its return type, along with the classes and enums of a type builder, is described
+  to the model. Nothing is sent to the model.
+
+  ## Parameters
+    - `function_name`: The name of the BAML function
+    - `opts`: A map of options
+      - `path`: The path to the BAML source files
+      - `runtime`: A `BamlElixir.Runtime` to use instead of loading `path`
+      - `tb`: A type builder, as for `call/3`
+
+  ## Examples
+      {:ok, output_format} =
+        BamlElixir.Client.output_format("ExtractResume", %{
+          tb: [{:class, "Resume", [%{name: "skills", type: "string[]"}]}]
+        })
+  """
+  @spec output_format(String.t(), map()) :: {:ok, String.t()} | {:error, term()}
+  def output_format(function_name, opts \\ %{}) do
+    {path, _collectors, _client_registry, tb} = prepare_opts(opts)
+
+    case BamlElixir.Native.output_format(function_name, path, tb) do
+      {:error, error} -> {:error, error}
+      output_format -> {:ok, output_format}
+    end
+  end
+
   @doc """
   Streams a BAML function asynchronously.
 
@@ -84,8 +219,15 @@ defmodule BamlElixir.Client do
     - `callback`: A function that will be called with the result of the function
     - `opts`: A map of options
       - `path`: The path to the BAML source file
-      - `collectors`: A list of collectors to use
-      - `llm_client`: The name of the LLM client to use
+      - `collectors`: A list of collectors to use. Collectors log the requests BAML sends, so
+        calls using "mock" or "elixir" clients or a runtime replaying a cassette can't have
+        any.
+      - `llm_client`: The name of the LLM client to use, or a `"provider/model"` shorthand
+      - `client_registry`: A map of clients defined at runtime, see `call/3`
+      - `client_options`: Per-call client option overrides, see `call/3`
+      - `timeout`: How long to wait for each partial or final result in milliseconds,
+        `:infinity` by default. The callback gets `{:error, :timeout}` when the stream times
+        out, and the stream is cancelled like calls are, see `call/3`.
 
   """
   def stream(function_name, args, callback, opts \\ %{}) do
@@ -93,8 +235,10 @@ defmodule BamlElixir.Client do
     args = to_map(args)
 
     spawn_link(fn ->
-      start_sync_stream(self(), ref, function_name, args, opts)
-      handle_stream_result(ref, callback, opts)
+      case start_sync_stream(self(), ref, function_name, args, opts) do
+        {:ok, call} -> handle_stream_result(call, ref, callback, opts)
+        {:error, _} = error -> callback.(error)
+      end
     end)
   end
 
@@ -108,38 +252,63 @@ defmodule BamlElixir.Client do
     end
   end
 
+  defp start_call({:error, _} = error), do: error
+  defp start_call(call), do: {:ok, call}
+
+  defp receive_result(call, ref, timeout) do
+    receive do
+      {^ref, result} -> result
+    after
+      timeout -> cancel(call, ref)
+    end
+  end
+
+  # No message of a cancelled call arrives once it's cancelled, so the ones
+  # already sent can be flushed
+  defp cancel(call, ref) do
+    BamlElixir.Native.cancel(call)
+    flush(ref)
+    {:error, :timeout}
+  end
+
+  defp flush(ref) do
+    receive do
+      {^ref, _} -> flush(ref)
+    after
+      0 -> :ok
+    end
+  end
+
   defp start_sync_stream(pid, ref, function_name, args, opts) do
     {path, collectors, client_registry, tb} = prepare_opts(opts)
 
-    spawn_link(fn ->
-      result =
-        BamlElixir.Native.stream(
-          pid,
-          ref,
-          function_name,
-          args,
-          path,
-          collectors,
-          client_registry,
-          tb
-        )
-
-      send(pid, {ref, result})
-    end)
+    # The stream runs on a worker thread, which sends its partial and final
+    # results to `pid`
+    BamlElixir.Native.stream(
+      pid,
+      ref,
+      function_name,
+      args,
+      path,
+      collectors,
+      client_registry,
+      tb
+    )
+    |> start_call()
   end
 
-  defp handle_stream_result(ref, callback, opts) do
+  defp handle_stream_result(call, ref, callback, opts) do
     receive do
       {^ref, {:partial, result}} ->
         result =
           if opts[:parse] != false do
-            parse_result(result, opts[:prefix], opts[:tb])
+            parse_result(result, opts[:prefix])
           else
             result
           end
 
         callback.({:partial, result})
-        handle_stream_result(ref, callback, opts)
+        handle_stream_result(call, ref, callback, opts)
 
       {^ref, {:error, _} = msg} ->
         callback.(msg)
@@ -147,12 +316,14 @@ defmodule BamlElixir.Client do
       {^ref, {:done, result}} ->
         result =
           if opts[:parse] != false do
-            parse_result(result, opts[:prefix], opts[:tb])
+            parse_result(result, opts[:prefix])
           else
             result
           end
 
         callback.({:done, result})
+    after
+      opts[:timeout] || :infinity -> callback.(cancel(call, ref))
     end
   end
 
@@ -166,6 +337,15 @@ defmodule BamlElixir.Client do
       field_types = get_field_types(fields, caller)
       module_name = Module.concat([module, type_name])
 
+      # Fields added by a type builder go under `__dynamic__`
+      {field_names, field_types} =
+        if dynamic do
+          {field_names ++ [__dynamic__: quote(do: %{})],
+           field_types ++ [__dynamic__: quote(do: map())]}
+        else
+          {field_names, field_types}
+        end
+
       quote do
         defmodule unquote(module_name) do
           defstruct unquote(field_names)
@@ -206,7 +386,8 @@ defmodule BamlElixir.Client do
   end
 
   # Every function in the BAML source file is converted to an Elixir module
-  # which has a `call/2` function and a `stream/3` function.
+  # which has a `call/2` function, a `stream/3` function and an `output_format/1`
+  # function.
   defp generate_function_modules(functions, path, caller) do
     module = caller.module
 
@@ -247,6 +428,12 @@ defmodule BamlElixir.Client do
 
             BamlElixir.Client.stream(unquote(function_name), args, callback, opts)
           end
+
+          @spec output_format(map()) :: {:ok, String.t()} | {:error, term()}
+          def output_format(opts \\ %{}) do
+            opts = Map.put(opts, :path, BamlElixir.Client.app_path(unquote(path)))
+            BamlElixir.Client.output_format(unquote(function_name), opts)
+          end
         end
       end
     end
@@ -355,49 +542,83 @@ defmodule BamlElixir.Client do
   end
 
   defp prepare_opts(opts) do
-    path = opts[:path] || "baml_src"
+    path =
+      case opts[:runtime] do
+        %BamlElixir.Runtime{reference: reference} -> reference
+        nil -> opts[:path] || "baml_src"
+      end
+
     collectors = (opts[:collectors] || []) |> Enum.map(fn collector -> collector.reference end)
-    client_registry = opts[:llm_client] && %{primary: opts[:llm_client]}
+    client_registry = prepare_client_registry(opts)
     tb = prepare_type_builder(opts[:tb])
     {path, collectors, client_registry, tb}
   end
 
-  defp prepare_type_builder(tb) do
-    case tb do
-      [_ | _] ->
-        tb
+  defp prepare_client_registry(opts) do
+    client_registry =
+      case opts[:client_registry] do
+        %BamlElixir.ClientRegistry{reference: reference} -> %{registry: reference}
+        client_registry -> client_registry
+      end
 
-      _ ->
-        nil
-    end
+    client_registry
+    |> put_client_registry_key(:primary, opts[:llm_client])
+    |> put_client_registry_key(:overrides, opts[:client_options])
+    |> put_client_registry_key(:headers, opts[:headers])
   end
 
-  # If type builder is provided, return as map instead of struct
-  defp parse_result(%{:__baml_class__ => _class_name} = result, _prefix, [_ | _]) do
-    result
+  defp put_client_registry_key(client_registry, _key, nil), do: client_registry
+
+  defp put_client_registry_key(client_registry, key, value) do
+    (client_registry || %{})
+    |> Map.drop([Atom.to_string(key)])
+    |> Map.put(key, value)
+  end
+
+  defp prepare_type_builder(tb) do
+    case tb do
+      %BamlElixir.TypeBuilder{reference: reference} -> reference
+      [] -> nil
+      tb -> tb
+    end
   end
 
-  defp parse_result(%{:__baml_class__ => class_name} = result, prefix, tb) do
+  # Classes only defined by a type builder have no module and stay maps
+  defp parse_result(%{:__baml_class__ => class_name} = result, prefix) do
     module = Module.concat(prefix, class_name)
-    values = Enum.map(result, fn {key, value} -> {key, parse_result(value, prefix, tb)} end)
-    struct(module, values)
+    values = Map.new(result, fn {key, value} -> {key, parse_field(key, value, prefix)} end)
+
+    if Code.ensure_loaded?(module) and function_exported?(module, :__struct__, 0) do
+      struct(module, values)
+    else
+      values
+    end
   end
 
-  defp parse_result(%{:__baml_enum__ => _, :value => value}, _prefix, _tb) do
+  defp parse_result(%{:__baml_enum__ => _, :value => value}, _prefix) do
     String.to_atom(value)
   end
 
-  defp parse_result(list, prefix, tb) when is_list(list) do
-    Enum.map(list, fn item -> parse_result(item, prefix, tb) end)
+  defp parse_result(list, prefix) when is_list(list) do
+    Enum.map(list, fn item -> parse_result(item, prefix) end)
   end
 
-  defp parse_result(result, _prefix, _tb) do
+  defp parse_result(result, _prefix) do
     result
   end
 
+  # Fields added to `@@dynamic` classes at runtime are under `__dynamic__`
+  defp parse_field(:__dynamic__, dynamic, prefix) do
+    Map.new(dynamic, fn {key, value} -> {key, parse_result(value, prefix)} end)
+  end
+
+  defp parse_field(_key, value, prefix), do: parse_result(value, prefix)
+
   defp to_map(args) when is_struct(args) do
+    {dynamic, args} = args |> Map.from_struct() |> Map.pop(:__dynamic__, %{})
+
     args
-    |> Map.from_struct()
+    |> Map.merge(dynamic)
     |> to_map()
   end
 
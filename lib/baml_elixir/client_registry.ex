@@ -0,0 +1,50 @@
+defmodule BamlElixir.ClientRegistry do
+  @moduledoc """
+  A client registry validated once and shared between calls.
+
+  Pass it to calls with the `client_registry` option instead of a map:
+
+      {:ok, registry} =
+        BamlElixir.ClientRegistry.new(%{
+          primary: "MyClient",
+          clients: [
+            %{name: "MyClient", provider: "openai", options: %{model: "gpt-4o-mini"}}
+          ]
+        })
+
+      MyApp.BamlClient.ExtractResume.call(%{resume: resume}, %{client_registry: registry})
+
+  The `llm_client`, `client_options` and `headers` options of a call still apply on top of it.
+  """
+
+  defstruct reference: nil
+
+  @doc """
+  Validates `client_registry` and returns it as a reusable registry.
+
+  The map takes the same keys as the `client_registry` option of
+  `BamlElixir.Client.call/3`, see "Client registry" there:
+
+    - `primary`: The name of the client functions use
+    - `clients`: A list of client definitions, each a map of `name`, `provider`, `options`
+      and an optional `retry_policy`. `fallback` and `round-robin` clients take a `strategy`
+      list instead of `options`, `elixir` clients a `handler` pid and `mock` clients their
+      `responses`.
+    - `retry_policies`: A list of retry policies, each a map of `name`, `max_retries`,
+      `strategy` (`"constant_delay"` or `"exponential_backoff"`), `delay_ms`, `multiplier`
+      and `max_delay_ms`
+    - `overrides`: A map of client names to options merged on top of the client's options
+    - `headers`: A map of extra HTTP headers added to the requests of every client
+
+  Providers, options and strategies are checked up front. Invalid registries return
+  `{:error, %{kind: :invalid_client_registry, client: client, option: option, message: message}}`,
+  where `client` and `option` point at the offending entry or are `nil`.
+  """
+  @spec new(map()) :: {:ok, %__MODULE__{}} | {:error, term()}
+  def new(client_registry) when is_map(client_registry) do
+    case BamlElixir.Native.client_registry_new(client_registry) do
+      {:error, error} -> {:error, error}
+      reference -> {:ok, %__MODULE__{reference: reference}}
+    end
+  end
+end
@@ -11,17 +11,39 @@ defmodule BamlElixir.Native do
       "x86_64-unknown-linux-gnu"
     ]
 
-  def call(_function_name, _args, _path, _collectors, _client_registry, _tb),
+  def call(_pid, _reference, _function_name, _args, _path, _collectors, _client_registry, _tb),
     do: :erlang.nif_error(:nif_not_loaded)
 
   def stream(_pid, _reference, _function_name, _args, _path, _collectors, _client_registry, _tb),
     do: :erlang.nif_error(:nif_not_loaded)
 
+  def cancel(_call), do: :erlang.nif_error(:nif_not_loaded)
+
   def collector_new(_name), do: :erlang.nif_error(:nif_not_loaded)
 
   def collector_usage(_collector), do: :erlang.nif_error(:nif_not_loaded)
 
   def collector_last_function_log(_collector), do: :erlang.nif_error(:nif_not_loaded)
 
+  def client_registry_new(_client_registry), do: :erlang.nif_error(:nif_not_loaded)
+
+  def type_builder_new(_runtime, _tb), do: :erlang.nif_error(:nif_not_loaded)
+
+  def type_builder_add_class(_tb, _name, _attributes), do: :erlang.nif_error(:nif_not_loaded)
+
+  def type_builder_add_property(_tb, _class, _property), do: :erlang.nif_error(:nif_not_loaded)
+
+  def type_builder_add_enum_value(_tb, _enum, _value), do: :erlang.nif_error(:nif_not_loaded)
+
+  def type_builder_from_json_schema(_schema, _name), do: :erlang.nif_error(:nif_not_loaded)
+
+  def output_format(_function_name, _path, _tb), do: :erlang.nif_error(:nif_not_loaded)
+
+  def runtime_new(_path, _opts), do: :erlang.nif_error(:nif_not_loaded)
+
+  def runtime_circuit_breakers(_runtime), do: :erlang.nif_error(:nif_not_loaded)
+
+  def reply(_reply, _term), do: :erlang.nif_error(:nif_not_loaded)
+
   def parse_baml(_path), do: :erlang.nif_error(:nif_not_loaded)
 end
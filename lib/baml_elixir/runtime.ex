@@ -0,0 +1,108 @@
+defmodule BamlElixir.Runtime do
+  @moduledoc """
+  A BAML runtime loaded once and shared between calls.
+
+  Pass it to calls with the `runtime` option instead of a `path`:
+
+      {:ok, runtime} =
+        BamlElixir.Runtime.new("priv/baml_src",
+          limits: %{"GPT4" => %{max_concurrency: 4, requests_per_minute: 60}}
+        )
+
+      MyApp.BamlClient.ExtractResume.call(%{resume: resume}, %{runtime: runtime})
+
+  Requests BAML sends can't be seen or rewritten before they're sent. To handle the requests
+  of a client in Elixir, e.g. to sign or redact them, use an `elixir` client instead, see
+  `BamlElixir.Client.call/3`.
+
+  ## Options
+    - `retry_policies`: Retry policies loaded with the BAML source files, for the clients of
+      client registries passed to calls using the runtime, in the same format as the
+      `retry_policies` of a client registry. Registries used with the runtime can only name
+      retry policies declared here or in the BAML source files.
+    - `limits`: A map of client names to limits enforced across all calls using the runtime.
+      Every request waits for the limits of the client it's sent to, including retries and
+      the clients of fallback and round-robin strategies, and the tokens it used are charged
+      once it's done. Calls wait on a thread of their own, not on a scheduler.
+      - `max_concurrency`: The maximum number of requests in flight
+      - `requests_per_minute`: The maximum number of requests per minute
+      - `tokens_per_minute`: The maximum number of input and output tokens per minute
+      - `queue_timeout_ms`: How long a request may wait. A request that times out is skipped
+        like a failed one, and calls whose requests were all skipped return
+        `{:error, %{kind: :queue_timeout, client: client, message: message}}`.
+    - `circuit_breaker`: Tracks the failed requests of every client and opens a client's
+      circuit after too many of them. While open, requests to the client are skipped, so
+      fallback and round-robin clients move on. Calls all of whose clients, including those
+      of nested strategies, have open circuits return
+      `{:error, %{kind: :circuit_open, client: client, retry_after_ms: ms, message: message}}`
+      for the client they start with.
+      Once `open_ms` has passed, a single request probes the client and closes the circuit
+      again if it succeeds.
+      - `failure_threshold`: Consecutive failures that open the circuit, defaults to 5
+      - `error_rate`: Rate of failures over the last `window` requests that opens the
+        circuit, defaults to 0.5
+      - `window`: Number of requests the error rate is computed over, defaults to 20
+      - `open_ms`: How long the circuit stays open, defaults to 30 seconds
+      With limits or a circuit breaker, each request of a call is made by BAML as a call of
+      its own, which collectors log as a function log of its own.
+    - `secrets`: Resolves the environment variables the clients of a call refer to, like
+      `env.OPENAI_API_KEY` or the API key a provider reads by default, through a process
+      instead of the OS environment. Only the clients the call may use are resolved, and
+      the process is asked for all of them at once. It gets
+      `{:baml_elixir_secret, reply, name}` messages and must answer with `reply/2`.
+      It can't be the process making the call, since calls wait for the answer.
+      - `resolver`: The pid of the process
+      - `ttl_ms`: How long answers are cached, defaults to 5 minutes
+      - `timeout_ms`: How long to wait for an answer, defaults to 5 seconds. Calls that
+        time out return `{:error, %{kind: :secret_unavailable, name: name, message: message}}`.
+    - `cassette`: Records the requests of every call using the runtime and the responses to
+      them to a JSON Lines file, or serves the responses recorded in one instead of calling the
+      providers. Every request is recorded, whether BAML sent it or a mock or elixir client
+      answered it. Each request is appended to the file once its response is done, without
+      its headers, along with the text BAML parsed from the response or why it failed, and
+      the status and body the provider sent when they're known. Replayed calls can't have
+      collectors, since BAML sends no requests for them.
+      - `path`: The path of the cassette file, which recording empties first
+      - `mode`: `:record` or `:replay`
+      - `match_on`: What a request must have in common with a recorded one to be replayed,
+        any of `:function`, `:prompt` and `:client`. Defaults to `[:function, :prompt]`,
+        where `:prompt` compares the hash of the rendered request body. Recorded responses
+        are replayed once each, in order, and requests no unreplayed response matches fail.
+      - `repeat`: `true` to replay the last response matching to any further requests
+  """
+
+  defstruct reference: nil
+
+  def new(path, opts \\ []) do
+    case BamlElixir.Native.runtime_new(BamlElixir.Client.app_path(path), Map.new(opts)) do
+      {:error, error} -> {:error, error}
+      reference -> {:ok, %__MODULE__{reference: reference}}
+    end
+  end
+
+  @doc """
+  Returns the circuit breaker state of each client the runtime has called, e.g.
+
+      %{
+        "GPT4" => %{state: :open, consecutive_failures: 5, error_rate: 0.25, retry_after_ms: 12_000}
+      }
+
+  `state` is `:closed`, `:open` or `:half_open`, and `retry_after_ms` is `nil` unless open.
+  """
+  def circuit_breakers(%__MODULE__{reference: reference}) do
+    BamlElixir.Native.runtime_circuit_breakers(reference)
+  end
+
+  @doc """
+  Answers a message from the runtime, like `{:baml_elixir_secret, reply, name}`.
+
+  Secrets are answered with `{:ok, value}`, `{:ok, value, ttl_ms}` to cache the value for
+  a different TTL than the runtime's, or `:error` to read it from the OS environment.
+
+  `elixir` clients answer requests with `{:ok, %{status: status, body: body}}`,
+  `{:error, reason}` or, for streamed requests, `{:chunk, data}` replies followed by `:done`.
+  """
+  def reply(reply, response) do
+    BamlElixir.Native.reply(reply, response)
+  end
+end
@@ -1,2 +1,95 @@
 defmodule BamlElixir.TypeBuilder do
+  @moduledoc """
+  A type builder validated once and shared between calls, instead of passing the list of
+  entries with every call.
+
+      {:ok, tb} =
+        BamlElixir.TypeBuilder.new(
+          [{:class, "TestPerson", [%{name: "name", type: "string"}]}],
+          path: "priv/baml_src"
+        )
+
+      :ok =
+        BamlElixir.TypeBuilder.add_property(tb, "NewEmployee", %{name: "person", type: "TestPerson"})
+
+      MyApp.BamlClient.CreateEmployee.call(%{}, %{tb: tb})
+
+  Entries, properties and values are the same as for the `tb` option of calls, and are
+  validated against the BAML source files of `path` or of a `BamlElixir.Runtime`. Classes,
+  properties and enum values added later apply to the calls started afterwards, and not to
+  the ones already running. Invalid ones return `{:error, %{kind: :invalid_type_builder, ...}}`.
+  """
+
+  defstruct reference: nil
+
+  @doc """
+  Creates a type builder from a list of entries.
+
+  ## Options
+    - `path`: The path to the BAML source files, defaults to `"baml_src"`
+    - `runtime`: A `BamlElixir.Runtime` to validate against instead of loading `path`
+  """
+  def new(entries \\ [], opts \\ []) when is_list(entries) do
+    runtime =
+      case opts[:runtime] do
+        %BamlElixir.Runtime{reference: reference} -> reference
+        nil -> BamlElixir.Client.app_path(opts[:path] || "baml_src")
+      end
+
+    case BamlElixir.Native.type_builder_new(runtime, entries) do
+      {:error, error} -> {:error, error}
+      reference -> {:ok, %__MODULE__{reference: reference}}
+    end
+  end
+
+  @doc """
+  Converts a JSON Schema, given as JSON or as a decoded map, into a list of entries for the
+  `tb` option of calls or for `new/2`.
+
+  Objects with properties become classes, named after their `title` or after the property
+  holding them, and properties are optional unless `required`. String enums become enums,
+  `oneOf`, `anyOf` and lists of types become unions, and `$ref`s to `$defs` or
+  `definitions` become types named after the definition, which may refer to themselves.
+  Descriptions are kept, and properties whose names aren't valid in BAML are renamed, with
+  their original name as an alias.
+
+  ## Options
+    - `name`: The name of the root type, e.g. a `@@dynamic` class to add the properties of
+      the schema to. Defaults to the schema's `title`.
+
+  Schemas that can't be converted return
+  `{:error, %{kind: :invalid_json_schema, path: path, message: message}}`, where `path`
+  points at the offending schema, like `"#/properties/address"`.
+  """
+  def from_json_schema(schema, opts \\ []) when is_binary(schema) or is_map(schema) do
+    case BamlElixir.Native.type_builder_from_json_schema(schema, opts[:name]) do
+      {:error, error} -> {:error, error}
+      entries -> {:ok, entries}
+    end
+  end
+
+  @doc """
+  Creates a class, or sets the `description` and `alias` of a class created before.
+  """
+  def add_class(%__MODULE__{reference: reference}, name, attributes \\ nil)
+      when is_binary(name) do
+    BamlElixir.Native.type_builder_add_class(reference, name, attributes)
+  end
+
+  @doc """
+  Adds a property to a class, like `%{name: "age", type: "int"}`, creating the class if
+  it doesn't exist.
+  """
+  def add_property(%__MODULE__{reference: reference}, class, property)
+      when is_binary(class) and is_map(property) do
+    BamlElixir.Native.type_builder_add_property(reference, class, property)
+  end
+
+  @doc """
+  Adds a value to an enum, either its name or a map like
+  `%{name: "Billing", description: "Payments"}`, creating the enum if it doesn't exist.
+  """
+  def add_enum_value(%__MODULE__{reference: reference}, enum, value) when is_binary(enum) do
+    BamlElixir.Native.type_builder_add_enum_value(reference, enum, value)
+  end
 end
@@ -0,0 +1,285 @@
+use rustler::{Error, ListIterator, MapIterator, Term};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::term_to_string;
+use crate::transport::{HttpRequest, RawResponse};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Match {
+    Function,
+    // The hash of the request body without its streaming options
+    Prompt,
+    Client,
+}
+
+enum Mode {
+    Record,
+    Replay,
+}
+
+struct Interaction {
+    client: String,
+    function: String,
+    prompt_hash: String,
+    // The text of the response, or why the request failed
+    outcome: Result<String, String>,
+}
+
+// Records the requests of the calls made with a runtime resource and their
+// responses to a JSON Lines file, or replays the responses recorded in one.
+// Every request is recorded, whether BAML, an Elixir process or a mock client
+// answered it, along with the status and body of the response as it was
+// received when they're known and the text BAML parsed from it. Each
+// interaction is appended to the file once it's done.
+//
+// Recorded requests leave out their headers, which hold API keys. Replayed
+// requests are matched to recorded ones by `match_on`, and each recorded
+// response is served once, in order, which is all that's replayed unless
+// `repeat` is set, in which case the last one matching is served to any
+// further requests.
+pub struct Cassette {
+    path: String,
+    match_on: Vec<Match>,
+    repeat: bool,
+    // Set when recording
+    file: Option<Mutex<File>>,
+    interactions: Vec<Interaction>,
+    served: Mutex<Vec<bool>>,
+}
+
+impl Cassette {
+    // Parses the cassette options of a runtime resource, like:
+    // %{path: "test/cassettes/resume.jsonl", mode: :replay, match_on: [:function, :prompt]}
+    // with an optional `repeat: true`
+    pub fn from_term(term: Term) -> Result<Cassette, Error> {
+        let iter = MapIterator::new(term).ok_or(Error::Term(Box::new("Cassette must be a map")))?;
+
+        let mut path = None;
+        let mut mode = None;
+        let mut match_on = vec![Match::Function, Match::Prompt];
+        let mut repeat = false;
+        for (key_term, value_term) in iter {
+            let key = term_to_string(key_term)?;
+            match key.as_str() {
+                "path" => path = Some(term_to_string(value_term)?),
+                "mode" => {
+                    mode = Some(match term_to_string(value_term)?.as_str() {
+                        "record" => Mode::Record,
+                        "replay" => Mode::Replay,
+                        _ => {
+                            return Err(Error::Term(Box::new(
+                                "Cassette mode must be :record or :replay",
+                            )))
+                        }
+                    })
+                }
+                "match_on" => {
+                    let invalid = || {
+                        Error::Term(Box::new(
+                            "Cassette match_on must be a list of :function, :prompt or :client",
+                        ))
+                    };
+                    let iter: ListIterator = value_term.decode().map_err(|_| invalid())?;
+                    match_on = iter
+                        .map(|item| match term_to_string(item)?.as_str() {
+                            "function" => Ok(Match::Function),
+                            "prompt" => Ok(Match::Prompt),
+                            "client" => Ok(Match::Client),
+                            _ => Err(invalid()),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                "repeat" => {
+                    repeat = value_term
+                        .decode()
+                        .map_err(|_| Error::Term(Box::new("Cassette repeat must be a boolean")))?
+                }
+                _ => {
+                    return Err(Error::Term(Box::new(format!(
+                        "Unknown cassette option: {}",
+                        key
+                    ))))
+                }
+            }
+        }
+
+        let path = path.ok_or(Error::Term(Box::new("Cassette needs a path")))?;
+        let mode = mode.ok_or(Error::Term(Box::new("Cassette needs a mode")))?;
+        let (file, interactions) = match mode {
+            Mode::Record => (Some(Mutex::new(create(&path)?)), Vec::new()),
+            Mode::Replay => (None, read(&path).map_err(|e| Error::Term(Box::new(e)))?),
+        };
+
+        Ok(Cassette {
+            path,
+            match_on,
+            repeat,
+            file,
+            served: Mutex::new(vec![false; interactions.len()]),
+            interactions,
+        })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.file.is_none()
+    }
+
+    // Returns the text of the response recorded for `request`, or why the
+    // recorded request failed
+    pub fn replay(&self, request: &HttpRequest) -> Result<String, String> {
+        let prompt_hash = prompt_hash(&request.body);
+        let mut served = self.served.lock().unwrap();
+
+        let matching: Vec<usize> = self
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, interaction)| {
+                self.match_on.iter().all(|field| match field {
+                    Match::Function => interaction.function == request.function,
+                    Match::Prompt => interaction.prompt_hash == prompt_hash,
+                    Match::Client => interaction.client == request.client,
+                })
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let unserved = matching.iter().find(|index| !served[**index]);
+        let index = match (unserved, matching.last()) {
+            (Some(index), _) => *index,
+            (None, Some(last)) if self.repeat => *last,
+            (None, Some(_)) => {
+                return Err(format!(
+                    "The {} responses recorded in {} for the request of {} to {} (prompt {}) \
+                     were all replayed",
+                    matching.len(),
+                    self.path,
+                    request.function,
+                    request.client,
+                    prompt_hash
+                ))
+            }
+            (None, None) => {
+                return Err(format!(
+                    "No response recorded in {} matches the request of {} to {} (prompt {})",
+                    self.path, request.function, request.client, prompt_hash
+                ))
+            }
+        };
+        served[index] = true;
+
+        self.interactions[index].outcome.clone()
+    }
+
+    // Appends `request` to the cassette file when recording, along with the
+    // response as it was received, if known, and its text or why it failed
+    pub fn record(
+        &self,
+        request: &HttpRequest,
+        response: Option<&RawResponse>,
+        outcome: &Result<String, String>,
+    ) -> Result<(), String> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut interaction = json!({
+            "client": request.client,
+            "function": request.function,
+            "prompt_hash": prompt_hash(&request.body),
+            "request": {
+                "method": request.method,
+                "url": request.url,
+                "stream": request.stream,
+                "body": serde_json::from_str::<Value>(&request.body)
+                    .unwrap_or_else(|_| Value::String(request.body.clone())),
+            },
+            "response": response.map(|response| json!({
+                "status": response.status,
+                "stream": response.stream,
+                "body": response.body,
+            })),
+        });
+        match outcome {
+            Ok(text) => interaction["text"] = json!(text),
+            Err(message) => interaction["error"] = json!(message),
+        }
+
+        let mut file = file.lock().unwrap();
+        writeln!(file, "{}", interaction)
+            .map_err(|e| format!("Failed to write cassette {}: {}", self.path, e))
+    }
+}
+
+// Creates the cassette file at `path`, or empties it
+fn create(path: &str) -> Result<File, Error> {
+    let failed = |e: std::io::Error| {
+        Error::Term(Box::new(format!(
+            "Failed to write cassette {}: {}",
+            path, e
+        )))
+    };
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir).map_err(failed)?;
+    }
+    File::create(path).map_err(failed)
+}
+
+fn read(path: &str) -> Result<Vec<Interaction>, String> {
+    let invalid = |message: String| format!("Invalid cassette {}: {}", path, message);
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read cassette {}: {}", path, e))?;
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let interaction: Value =
+                serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+            let field = |name: &str| {
+                interaction
+                    .get(name)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| invalid(format!("interaction without {}", name)))
+            };
+            let outcome = match (
+                interaction.get("text").and_then(Value::as_str),
+                interaction.get("error").and_then(Value::as_str),
+            ) {
+                (Some(text), _) => Ok(text.to_string()),
+                (None, Some(message)) => Err(message.to_string()),
+                (None, None) => {
+                    return Err(invalid("interaction without text or error".to_string()))
+                }
+            };
+            Ok(Interaction {
+                client: field("client")?,
+                function: field("function")?,
+                prompt_hash: field("prompt_hash")?,
+                outcome,
+            })
+        })
+        .collect()
+}
+
+// A stable hash of the rendered prompt, i.e. the request body without the
+// options that only differ between streamed and complete requests
+fn prompt_hash(body: &str) -> String {
+    let prompt = match serde_json::from_str::<Value>(body) {
+        Ok(Value::Object(mut object)) => {
+            object.remove("stream");
+            object.remove("stream_options");
+            Value::Object(object).to_string()
+        }
+        _ => body.to_string(),
+    };
+
+    // FNV-1a, which unlike the standard library's hasher is the same across
+    // Rust versions
+    let hash = prompt.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
@@ -0,0 +1,269 @@
+use rustler::{Encoder, Env, Error, MapIterator, NifMap, NifResult, Term};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{atoms, term_to_baml_value, term_to_string};
+
+pub struct Options {
+    failure_threshold: usize,
+    error_rate: f64,
+    window: usize,
+    open_for: Duration,
+}
+
+// Parses the circuit breaker options, which apply to every client:
+// %{failure_threshold: 5, error_rate: 0.5, window: 20, open_ms: 30_000}
+pub fn from_term(term: Term) -> Result<Options, Error> {
+    let iter =
+        MapIterator::new(term).ok_or(Error::Term(Box::new("Circuit breaker must be a map")))?;
+
+    let mut options = Options {
+        failure_threshold: 5,
+        error_rate: 0.5,
+        window: 20,
+        open_for: Duration::from_secs(30),
+    };
+    for (key_term, value_term) in iter {
+        let key = term_to_string(key_term)?;
+        let value = term_to_baml_value(value_term)?;
+        match (key.as_str(), value) {
+            ("failure_threshold", baml_types::BamlValue::Int(n)) if n > 0 => {
+                options.failure_threshold = n as usize
+            }
+            ("error_rate", baml_types::BamlValue::Float(rate)) if rate > 0.0 && rate <= 1.0 => {
+                options.error_rate = rate
+            }
+            ("error_rate", baml_types::BamlValue::Int(1)) => options.error_rate = 1.0,
+            ("window", baml_types::BamlValue::Int(n)) if n > 0 => options.window = n as usize,
+            ("open_ms", baml_types::BamlValue::Int(n)) if n > 0 => {
+                options.open_for = Duration::from_millis(n as u64)
+            }
+            ("failure_threshold" | "window" | "open_ms", _) => {
+                return Err(Error::Term(Box::new(format!(
+                    "Circuit breaker option {} must be a positive integer",
+                    key
+                ))))
+            }
+            ("error_rate", _) => {
+                return Err(Error::Term(Box::new(
+                    "Circuit breaker option error_rate must be a number between 0 and 1",
+                )))
+            }
+            _ => {
+                return Err(Error::Term(Box::new(format!(
+                    "Unknown circuit breaker option: {}",
+                    key
+                ))))
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[derive(NifMap)]
+struct CircuitOpen {
+    kind: rustler::Atom,
+    client: String,
+    retry_after_ms: u64,
+    message: String,
+}
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    // The open period is over and a single probe request decides whether the
+    // circuit closes again.
+    HalfOpen { probing: bool },
+}
+
+struct ClientCircuit {
+    state: State,
+    consecutive_failures: usize,
+    // Outcomes of the last `window` requests, true for failures
+    outcomes: VecDeque<bool>,
+}
+
+impl ClientCircuit {
+    fn new() -> Self {
+        ClientCircuit {
+            state: State::Closed,
+            consecutive_failures: 0,
+            outcomes: VecDeque::new(),
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            0.0
+        } else {
+            self.outcomes.iter().filter(|failed| **failed).count() as f64
+                / self.outcomes.len() as f64
+        }
+    }
+}
+
+// Tracks failures per client across all calls sharing a runtime resource.
+//
+// A client's circuit opens after `failure_threshold` consecutive failed
+// requests, or once its last `window` requests fail at `error_rate` or more.
+// While open, requests to the client are skipped, so fallback and round-robin
+// clients move on to their next client. After `open_for`, one request is let
+// through as a probe, whose outcome closes the circuit or opens it again.
+pub struct CircuitBreaker {
+    options: Option<Options>,
+    circuits: Mutex<HashMap<String, ClientCircuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: Option<Options>) -> Self {
+        CircuitBreaker {
+            options,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.options.is_some()
+    }
+
+    // Lets a request to `client` through, or returns how long until its
+    // circuit lets requests through again. Requests let through must be
+    // recorded or released.
+    pub fn pass(&self, client: &str) -> Result<(), Duration> {
+        if self.options.is_none() {
+            return Ok(());
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(client.to_string())
+            .or_insert_with(ClientCircuit::new);
+        if let State::Open { until } = circuit.state {
+            let now = Instant::now();
+            if now < until {
+                return Err(until - now);
+            }
+            circuit.state = State::HalfOpen { probing: false };
+        }
+
+        match circuit.state {
+            State::HalfOpen { probing: false } => {
+                circuit.state = State::HalfOpen { probing: true };
+                Ok(())
+            }
+            // Another request is probing the client
+            State::HalfOpen { probing: true } => Err(Duration::ZERO),
+            State::Closed | State::Open { .. } => Ok(()),
+        }
+    }
+
+    // How long until one of `clients` lets requests through again, if all of
+    // their circuits are open. Unlike `pass`, this doesn't take probes.
+    pub fn retry_after(&self, clients: &[String]) -> Option<Duration> {
+        self.options.as_ref()?;
+        let circuits = self.circuits.lock().unwrap();
+        let now = Instant::now();
+
+        let mut retry_after = Duration::MAX;
+        for client in clients {
+            match circuits.get(client).map(|circuit| &circuit.state) {
+                Some(State::Open { until }) if now < *until => {
+                    retry_after = retry_after.min(*until - now)
+                }
+                Some(State::HalfOpen { probing: true }) => retry_after = Duration::ZERO,
+                _ => return None,
+            }
+        }
+        (!clients.is_empty()).then_some(retry_after)
+    }
+
+    // Records whether a request `pass` let through failed
+    pub fn record(&self, client: &str, failed: bool) {
+        let Some(options) = &self.options else {
+            return;
+        };
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(client.to_string())
+            .or_insert_with(ClientCircuit::new);
+
+        circuit.outcomes.push_back(failed);
+        while circuit.outcomes.len() > options.window {
+            circuit.outcomes.pop_front();
+        }
+        if failed {
+            circuit.consecutive_failures += 1;
+        } else {
+            circuit.consecutive_failures = 0;
+        }
+
+        let now = Instant::now();
+        match circuit.state {
+            State::HalfOpen { .. } if failed => {
+                circuit.state = State::Open {
+                    until: now + options.open_for,
+                }
+            }
+            State::HalfOpen { .. } => {
+                circuit.state = State::Closed;
+                circuit.outcomes.clear();
+            }
+            State::Closed
+                if circuit.consecutive_failures >= options.failure_threshold
+                    || (circuit.outcomes.len() >= options.window
+                        && circuit.error_rate() >= options.error_rate) =>
+            {
+                circuit.state = State::Open {
+                    until: now + options.open_for,
+                }
+            }
+            // Requests sent before the circuit opened leave it open
+            State::Closed | State::Open { .. } => {}
+        }
+    }
+
+    // Releases a request `pass` let through that wasn't sent after all, like
+    // one that timed out waiting for the limits of the client
+    pub fn release(&self, client: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(client) {
+            if let State::HalfOpen { .. } = circuit.state {
+                circuit.state = State::HalfOpen { probing: false };
+            }
+        }
+    }
+
+    pub fn encode_state<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let circuits = self.circuits.lock().unwrap();
+        let now = Instant::now();
+
+        let mut map = Term::map_new(env);
+        for (client, circuit) in circuits.iter() {
+            let (state, retry_after_ms) = match circuit.state {
+                State::Closed => (atoms::closed(), None),
+                State::Open { until } => (
+                    atoms::open(),
+                    Some(until.saturating_duration_since(now).as_millis() as u64),
+                ),
+                State::HalfOpen { .. } => (atoms::half_open(), None),
+            };
+            let client_map = Term::map_new(env)
+                .map_put(atoms::state(), state)?
+                .map_put(atoms::consecutive_failures(), circuit.consecutive_failures)?
+                .map_put(atoms::error_rate(), circuit.error_rate())?
+                .map_put(atoms::retry_after_ms(), retry_after_ms)?;
+            map = map.map_put(client.encode(env), client_map)?;
+        }
+        Ok(map)
+    }
+}
+
+pub fn circuit_open(client: &str, retry_after: Duration) -> Error {
+    Error::Term(Box::new(CircuitOpen {
+        kind: atoms::circuit_open(),
+        client: client.to_string(),
+        retry_after_ms: retry_after.as_millis() as u64,
+        message: format!("Circuit of client {} is open", client),
+    }))
+}
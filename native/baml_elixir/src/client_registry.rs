@@ -0,0 +1,1047 @@
+use baml_runtime::client_registry::{ClientProperty, ClientProvider, ClientRegistry};
+use baml_runtime::BamlRuntime;
+use baml_types::{BamlMap, BamlValue};
+use internal_llm_client::ClientSpec;
+use rustler::{Error, ListIterator, LocalPid, MapIterator, NifMap, Resource, ResourceArc, Term};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clients::{self, Strategy};
+use crate::mock::Mock;
+use crate::transport::{Api, Handler};
+use crate::{atoms, term_to_baml_value, term_to_string};
+
+const STRATEGY_PROVIDERS: [&str; 2] = ["fallback", "round-robin"];
+// Providers whose requests are signed by their SDK rather than sent with the
+// headers of their options
+const PROVIDERS_WITHOUT_HEADERS: [&str; 1] = ["aws-bedrock"];
+
+#[rustler::resource_impl()]
+impl Resource for ClientRegistryResource {}
+
+// A client registry validated once and shared between calls.
+pub struct ClientRegistryResource {
+    pub spec: Spec,
+}
+
+impl ClientRegistryResource {
+    pub fn new(term: Term) -> Result<ResourceArc<ClientRegistryResource>, Error> {
+        let spec = Spec::from_term(term)?.unwrap_or_default();
+        Ok(ResourceArc::new(ClientRegistryResource { spec }))
+    }
+}
+
+// Returned as `{:error, %{kind: :invalid_client_registry, ...}}` when a client
+// registry doesn't validate. `client` and `option` point at the offending entry.
+#[derive(NifMap)]
+struct InvalidClientRegistry {
+    kind: rustler::Atom,
+    client: Option<String>,
+    option: Option<String>,
+    message: String,
+}
+
+fn invalid(client: Option<&str>, option: Option<&str>, message: String) -> Error {
+    Error::Term(Box::new(InvalidClientRegistry {
+        kind: atoms::invalid_client_registry(),
+        client: client.map(str::to_string),
+        option: option.map(str::to_string),
+        message,
+    }))
+}
+
+#[derive(Clone)]
+struct ClientDefinition {
+    name: String,
+    provider: String,
+    retry_policy: Option<String>,
+    options: BamlMap<String, BamlValue>,
+    // Set for "elixir" clients, whose `provider` is then the API whose
+    // requests BAML builds for the handler
+    handler: Option<Handler>,
+    // Set for "mock" clients, which are built like "elixir" ones
+    mock: Option<Arc<Mock>>,
+}
+
+// The validated contents of a client registry map like:
+// %{
+//   primary: "MyClient",
+//   retry_policies: [
+//     %{name: "Exponential", max_retries: 3, strategy: "exponential_backoff", delay_ms: 200}
+//   ],
+//   clients: [
+//     %{name: "MyClient", provider: "openai", options: %{model: "gpt-4o"}, retry_policy: "Exponential"},
+//     %{name: "MyFallback", provider: "fallback", strategy: ["MyClient", "GPT4"]},
+//     %{name: "InProcess", provider: "elixir", handler: pid, options: %{api: "openai-generic"}},
+//     %{name: "Fake", provider: "mock", responses: %{"ExtractResume" => "{}"}, latency_ms: 50}
+//   ],
+//   overrides: %{"GPT4" => %{temperature: 0.2}},
+//   headers: %{"x-tenant-id" => "acme"}
+// }
+//
+// The map may also hold a `registry` key with a ClientRegistryResource, whose
+// contents the rest of the map is added to. A ClientRegistryResource can be
+// given in place of the map too.
+#[derive(Clone, Default)]
+pub struct Spec {
+    primary: Option<String>,
+    clients: Vec<ClientDefinition>,
+    // Names and BAML source of the retry policies
+    retry_policies: Vec<(String, String)>,
+    overrides: Vec<(String, BamlMap<String, BamlValue>)>,
+    headers: BamlMap<String, BamlValue>,
+}
+
+impl Spec {
+    pub fn from_term(term: Term) -> Result<Option<Spec>, Error> {
+        if term.is_atom() && term.decode::<rustler::Atom>()? == atoms::nil() {
+            return Ok(None);
+        }
+
+        if let Ok(resource) = term.decode::<ResourceArc<ClientRegistryResource>>() {
+            return Ok(Some(resource.spec.clone()));
+        }
+
+        if !term.is_map() {
+            return Err(invalid(
+                None,
+                None,
+                "Client registry must be nil, a map or a client registry".to_string(),
+            ));
+        }
+
+        let mut base = Spec::default();
+        let mut spec = Spec::default();
+        let iter = MapIterator::new(term).ok_or(Error::Term(Box::new("Invalid registry map")))?;
+        for (key_term, value_term) in iter {
+            let key = term_to_string(key_term)?;
+            match key.as_str() {
+                "registry" => {
+                    let resource = value_term
+                        .decode::<ResourceArc<ClientRegistryResource>>()
+                        .map_err(|_| {
+                            invalid(None, None, "registry must be a client registry".to_string())
+                        })?;
+                    base = resource.spec.clone();
+                }
+                "primary" => spec.primary = Some(term_to_string(value_term)?),
+                "retry_policies" => {
+                    let iter: ListIterator = value_term.decode().map_err(|_| {
+                        invalid(
+                            None,
+                            None,
+                            "Registry retry policies must be a list".to_string(),
+                        )
+                    })?;
+                    for policy_term in iter {
+                        spec.retry_policies
+                            .push(retry_policy_from_term(policy_term)?);
+                    }
+                }
+                "clients" => {
+                    let iter: ListIterator = value_term.decode().map_err(|_| {
+                        invalid(None, None, "Registry clients must be a list".to_string())
+                    })?;
+                    for client_term in iter {
+                        let client = client_from_term(client_term)?;
+                        validate_client(&client)?;
+                        spec.clients.push(client);
+                    }
+                }
+                "overrides" => {
+                    let iter = MapIterator::new(value_term).ok_or(invalid(
+                        None,
+                        None,
+                        "Registry overrides must be a map".to_string(),
+                    ))?;
+                    for (name_term, options_term) in iter {
+                        let name = term_to_string(name_term)?;
+                        match term_to_baml_value(options_term)? {
+                            BamlValue::Map(options) => {
+                                validate_options(&name, &options)?;
+                                spec.overrides.push((name, options));
+                            }
+                            _ => {
+                                return Err(invalid(
+                                    Some(&name),
+                                    None,
+                                    format!("Overrides of client {} must be a map", name),
+                                ))
+                            }
+                        }
+                    }
+                }
+                "headers" => {
+                    let iter = MapIterator::new(value_term).ok_or(invalid(
+                        None,
+                        Some("headers"),
+                        "Headers must be a map".to_string(),
+                    ))?;
+                    for (name_term, value_term) in iter {
+                        let name = term_to_string(name_term)?;
+                        let value = term_to_string(value_term).map_err(|_| {
+                            invalid(
+                                None,
+                                Some("headers"),
+                                format!("Value of header {} must be a string", name),
+                            )
+                        })?;
+                        spec.headers.insert(name, BamlValue::String(value));
+                    }
+                }
+                _ => {
+                    return Err(invalid(
+                        None,
+                        None,
+                        format!("Unknown client registry key: {}", key),
+                    ))
+                }
+            }
+        }
+
+        base.primary = spec.primary.or(base.primary);
+        base.clients.extend(spec.clients);
+        base.retry_policies.extend(spec.retry_policies);
+        base.overrides.extend(spec.overrides);
+        base.headers.extend(spec.headers);
+        Ok(Some(base))
+    }
+
+    pub fn primary(&self) -> Option<&str> {
+        self.primary.as_deref()
+    }
+
+    // The strategy of `name` if it's a fallback or round-robin client, from the
+    // registry or else from the BAML source files of `runtime`.
+    pub fn strategy_of(&self, name: &str, runtime: &BamlRuntime) -> Option<Strategy> {
+        let overridden = self
+            .overrides
+            .iter()
+            .rev()
+            .find(|(client, options)| client == name && options.contains_key("strategy"));
+        let Some(client) = self.clients.iter().rev().find(|c| c.name == name) else {
+            let mut strategy = clients::find(runtime, name)?.strategy?;
+            if let Some((_, options)) = overridden {
+                let names = strategy_names(options.get("strategy"));
+                match &mut strategy {
+                    Strategy::Fallback(clients) | Strategy::RoundRobin { clients, .. } => {
+                        *clients = names
+                    }
+                }
+            }
+            return Some(strategy);
+        };
+
+        let options = overridden.map_or(&client.options, |(_, options)| options);
+        let clients = strategy_names(options.get("strategy"));
+        match client.provider.as_str() {
+            "fallback" => Some(Strategy::Fallback(clients)),
+            "round-robin" => {
+                let start = match client.options.get("start") {
+                    Some(BamlValue::Int(start)) => *start as usize,
+                    _ => 0,
+                };
+                Some(Strategy::RoundRobin { clients, start })
+            }
+            _ => None,
+        }
+    }
+
+    // The retry policy of `name`, from the overrides, the registry or else the
+    // BAML source files of `runtime`.
+    pub fn retry_policy_of(&self, name: &str, runtime: &BamlRuntime) -> Option<String> {
+        let overridden =
+            self.overrides.iter().rev().find_map(|(client, options)| {
+                (client == name).then(|| options.get("retry_policy"))?
+            });
+        if let Some(retry_policy) = overridden {
+            return match retry_policy {
+                BamlValue::String(retry_policy) => Some(retry_policy.clone()),
+                _ => None,
+            };
+        }
+
+        match self.clients.iter().rev().find(|c| c.name == name) {
+            Some(client) => client.retry_policy.clone(),
+            None => clients::find(runtime, name)?.retry_policy,
+        }
+    }
+
+    // The provider of `name`, which for "elixir" and "mock" clients is the API
+    // BAML builds their requests for.
+    pub fn provider_of(&self, name: &str, runtime: &BamlRuntime) -> Option<String> {
+        if let Some(client) = self.clients.iter().rev().find(|c| c.name == name) {
+            return Some(client.provider.clone());
+        }
+        match shorthand_client(name) {
+            Some(client) => Some(client.provider),
+            None => clients::find(runtime, name).map(|client| client.provider),
+        }
+    }
+
+    // The clients a call to `function_name` may send requests to, in the order
+    // they are tried, which are the client it starts with or the clients its
+    // strategy leads to.
+    pub fn reachable_clients(&self, function_name: &str, runtime: &BamlRuntime) -> Vec<String> {
+        let start = match &self.primary {
+            Some(primary) => Some(primary.clone()),
+            None => clients::function_client(runtime, function_name),
+        };
+
+        let mut clients = Vec::new();
+        if let Some(start) = start {
+            self.collect_clients(&start, runtime, &mut HashSet::new(), &mut clients);
+        }
+        clients
+    }
+
+    fn collect_clients(
+        &self,
+        name: &str,
+        runtime: &BamlRuntime,
+        visited: &mut HashSet<String>,
+        clients: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        match self.strategy_of(name, runtime) {
+            Some(strategy) => {
+                for client in strategy.clients() {
+                    self.collect_clients(client, runtime, visited, clients);
+                }
+            }
+            None => clients.push(name.to_string()),
+        }
+    }
+
+    // The environment variables the clients a call to `function_name` may use
+    // refer to, including the API keys their providers read by default, so
+    // that only those are resolved for the call
+    pub fn required_env_vars(&self, function_name: &str, runtime: &BamlRuntime) -> HashSet<String> {
+        let mut names = HashSet::new();
+        for client in self.reachable_clients(function_name, runtime) {
+            let definition = match self.clients.iter().rev().find(|c| c.name == client) {
+                Some(definition) => Some(definition.clone()),
+                None => shorthand_client(&client),
+            };
+            match definition {
+                Some(definition) => {
+                    if let Some(options) = definition
+                        .into_property()
+                        .ok()
+                        .and_then(|property| property.unresolved_options().ok())
+                    {
+                        names.extend(options.required_env_vars());
+                    }
+                }
+                None => names.extend(clients::required_env_vars(runtime, &client)),
+            }
+        }
+        names
+    }
+
+    // The handlers of "elixir" clients, by client name
+    pub fn handlers(&self) -> HashMap<String, Handler> {
+        self.clients
+            .iter()
+            .filter_map(|client| Some((client.name.clone(), client.handler?)))
+            .collect()
+    }
+
+    // The responses of "mock" clients, by client name
+    pub fn mocks(&self) -> HashMap<String, Arc<Mock>> {
+        self.clients
+            .iter()
+            .filter_map(|client| Some((client.name.clone(), client.mock.clone()?)))
+            .collect()
+    }
+
+    // Replaces the strategy of a fallback or round-robin client for the call
+    pub fn override_strategy(&mut self, name: String, strategy: Vec<String>) {
+        let mut options = BamlMap::new();
+        options.insert(
+            "strategy".to_string(),
+            BamlValue::List(strategy.into_iter().map(BamlValue::String).collect()),
+        );
+        self.overrides.push((name, options));
+    }
+
+    // The `retry_policies` rendered as BAML source, since retry policies can
+    // only be defined in BAML files. They are loaded with the source files.
+    pub fn retry_policies_source(&self) -> Option<String> {
+        (!self.retry_policies.is_empty()).then(|| {
+            self.retry_policies
+                .iter()
+                .map(|(_, source)| source.as_str())
+                .collect()
+        })
+    }
+
+    // A runtime resource is loaded once, so the retry policies of registries
+    // used with it must have been declared when it was created.
+    pub fn check_retry_policies(&self, declared: &HashSet<String>) -> Result<(), Error> {
+        match self
+            .retry_policies
+            .iter()
+            .find(|(name, _)| !declared.contains(name))
+        {
+            Some((name, _)) => Err(invalid(
+                None,
+                Some("retry_policies"),
+                format!(
+                    "Retry policy {} must be declared with the runtime's retry_policies to be \
+                     used with it",
+                    name
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Builds the ClientRegistry for a call to `function_name` with `runtime`,
+    // whose clients strategy clients may refer to and `overrides` may apply to
+    // alongside the ones defined in the registry. Calls run by the orchestrator
+    // apply retry policies themselves, so they are left out of the clients
+    // requests are sent to `without_retry_policies`.
+    pub fn build(
+        &self,
+        function_name: &str,
+        runtime: &BamlRuntime,
+        files: &HashMap<String, String>,
+        env_vars: &HashMap<String, String>,
+        without_retry_policies: bool,
+    ) -> Result<ClientRegistry, Error> {
+        let mut clients = self.clients.clone();
+
+        // Headers are added to every client the call may send requests to, the
+        // clients from the BAML source files included, as if they were
+        // overrides. Clients whose providers take no headers, and clients from
+        // the source files whose options can't be read, e.g. because an
+        // environment variable they refer to isn't set, are left as they are,
+        // so that they only fail the call if it gets to them.
+        let mut overrides = self.overrides.clone();
+        if !self.headers.is_empty() {
+            let mut options = BamlMap::new();
+            options.insert("headers".to_string(), BamlValue::Map(self.headers.clone()));
+            for name in self.reachable_clients(function_name, runtime) {
+                let provider = self.provider_of(&name, runtime).unwrap_or_default();
+                if PROVIDERS_WITHOUT_HEADERS.contains(&provider.as_str()) {
+                    continue;
+                }
+                let defined = clients.iter().any(|c| c.name == name)
+                    || overrides.iter().any(|(n, _)| n == &name)
+                    || shorthand_client(&name).is_some();
+                if !defined {
+                    match static_client(runtime, files, &name, env_vars) {
+                        Ok(client) => clients.push(client),
+                        Err(_) => continue,
+                    }
+                }
+                overrides.push((name, options.clone()));
+            }
+        }
+
+        // Overrides of clients from the BAML source files turn them into registry
+        // clients with the same name and the options BAML resolves for them,
+        // which take precedence over the static ones.
+        for (name, options) in &overrides {
+            let index = match clients.iter().position(|c| &c.name == name) {
+                Some(index) => index,
+                None => {
+                    let client = match shorthand_client(name) {
+                        Some(client) => client,
+                        None => static_client(runtime, files, name, env_vars)?,
+                    };
+                    clients.push(client);
+                    clients.len() - 1
+                }
+            };
+            let mut options = options.clone();
+            match options.shift_remove("retry_policy") {
+                Some(BamlValue::String(retry_policy)) => {
+                    clients[index].retry_policy = Some(retry_policy)
+                }
+                Some(_) => clients[index].retry_policy = None,
+                None => {}
+            }
+            merge_options(&mut clients[index].options, options);
+        }
+
+        if without_retry_policies {
+            for name in self.reachable_clients(function_name, runtime) {
+                match clients.iter().position(|c| c.name == name) {
+                    Some(index) => clients[index].retry_policy = None,
+                    None if clients::find(runtime, &name)
+                        .is_some_and(|client| client.retry_policy.is_some()) =>
+                    {
+                        let mut client = static_client(runtime, files, &name, env_vars)?;
+                        client.retry_policy = None;
+                        clients.push(client);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        let static_clients = clients::names(runtime);
+        let known_clients: HashSet<&str> = static_clients
+            .iter()
+            .map(String::as_str)
+            .chain(clients.iter().map(|c| c.name.as_str()))
+            .collect();
+        for client in &clients {
+            validate_strategy_clients(client, &known_clients)?;
+        }
+
+        let mut registry = ClientRegistry::new();
+        for client in clients {
+            registry.add_client(client.into_property()?);
+        }
+        if let Some(primary) = &self.primary {
+            registry.set_primary(primary.clone());
+        }
+
+        Ok(registry)
+    }
+}
+
+// A registry client standing in for the client `name` from the BAML source
+// files `files`, with the same provider, retry policy and options.
+fn static_client(
+    runtime: &BamlRuntime,
+    files: &HashMap<String, String>,
+    name: &str,
+    env_vars: &HashMap<String, String>,
+) -> Result<ClientDefinition, Error> {
+    let unknown = || {
+        invalid(
+            Some(name),
+            None,
+            format!("Cannot override options of unknown client {}", name),
+        )
+    };
+    let client = clients::find(runtime, name).ok_or_else(unknown)?;
+    let options = clients::options(files, name, env_vars)
+        .map_err(|message| invalid(Some(name), None, message))?
+        .ok_or_else(unknown)?;
+
+    Ok(ClientDefinition {
+        name: name.to_string(),
+        provider: client.provider,
+        retry_policy: client.retry_policy,
+        options,
+        handler: None,
+        mock: None,
+    })
+}
+
+fn strategy_names(strategy: Option<&BamlValue>) -> Vec<String> {
+    let Some(BamlValue::List(names)) = strategy else {
+        return Vec::new();
+    };
+    names
+        .iter()
+        .filter_map(|name| match name {
+            BamlValue::String(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Parses a retry policy, like
+// %{name: "Exponential", max_retries: 3, strategy: "exponential_backoff", delay_ms: 200},
+// into its name and its BAML source.
+pub fn retry_policy_from_term(term: Term) -> Result<(String, String), Error> {
+    let iter = MapIterator::new(term).ok_or(invalid(
+        None,
+        None,
+        "Retry policy must be a map".to_string(),
+    ))?;
+
+    let mut name = None;
+    let mut max_retries = None;
+    let mut strategy = "constant_delay".to_string();
+    let mut strategy_options = Vec::new();
+
+    for (key_term, value_term) in iter {
+        let key = term_to_string(key_term)?;
+        match key.as_str() {
+            "name" => name = Some(term_to_string(value_term)?),
+            "max_retries" => match term_to_baml_value(value_term)? {
+                BamlValue::Int(n) if n >= 0 => max_retries = Some(n),
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some(&key),
+                        "Retry policy max_retries must be a non-negative integer".to_string(),
+                    ))
+                }
+            },
+            "strategy" => strategy = term_to_string(value_term)?,
+            "delay_ms" | "max_delay_ms" => match term_to_baml_value(value_term)? {
+                BamlValue::Int(n) if n >= 0 => strategy_options.push((key, n.to_string())),
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some(&key),
+                        format!("Retry policy {} must be a non-negative integer", key),
+                    ))
+                }
+            },
+            "multiplier" => match term_to_baml_value(value_term)? {
+                BamlValue::Int(n) if n > 0 => strategy_options.push((key, n.to_string())),
+                BamlValue::Float(f) if f > 0.0 => strategy_options.push((key, f.to_string())),
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some(&key),
+                        "Retry policy multiplier must be a positive number".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(invalid(
+                    None,
+                    Some(&key),
+                    format!("Unknown retry policy key: {}", key),
+                ))
+            }
+        }
+    }
+
+    let name = name.ok_or(invalid(
+        None,
+        None,
+        "Retry policy is missing a name".to_string(),
+    ))?;
+    if !is_identifier(&name) {
+        return Err(invalid(
+            None,
+            None,
+            format!(
+                "Retry policy name {:?} must be a valid BAML identifier",
+                name
+            ),
+        ));
+    }
+    let max_retries = max_retries.ok_or(invalid(
+        None,
+        Some("max_retries"),
+        format!("Retry policy {} is missing max_retries", name),
+    ))?;
+
+    match strategy.as_str() {
+        "constant_delay" => {
+            if let Some((key, _)) = strategy_options
+                .iter()
+                .find(|(key, _)| key == "multiplier" || key == "max_delay_ms")
+            {
+                return Err(invalid(
+                    None,
+                    Some(key),
+                    format!(
+                        "Retry policy {} uses constant_delay, which does not support {}",
+                        name, key
+                    ),
+                ));
+            }
+        }
+        "exponential_backoff" => {}
+        _ => {
+            return Err(invalid(
+                None,
+                Some("strategy"),
+                format!(
+                    "Retry policy {} has an invalid strategy: {}",
+                    name, strategy
+                ),
+            ))
+        }
+    }
+
+    let mut source = format!(
+        "retry_policy {} {{\n  max_retries {}\n  strategy {{\n    type {}\n",
+        name, max_retries, strategy
+    );
+    for (key, value) in strategy_options {
+        source.push_str(&format!("    {} {}\n", key, value));
+    }
+    source.push_str("  }\n}\n\n");
+
+    Ok((name, source))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn client_from_term(term: Term) -> Result<ClientDefinition, Error> {
+    let iter =
+        MapIterator::new(term).ok_or(invalid(None, None, "Client must be a map".to_string()))?;
+
+    let mut name = None;
+    let mut provider = None;
+    let mut retry_policy = None;
+    let mut options = BamlMap::new();
+    let mut strategy = None;
+    let mut start = None;
+    let mut handler = None;
+    let mut timeout = Duration::from_secs(60);
+    let mut response = None;
+    let mut responses = None;
+    let mut latency = None;
+
+    for (key_term, value_term) in iter {
+        let key = term_to_string(key_term)?;
+        match key.as_str() {
+            "name" => name = Some(term_to_string(value_term)?),
+            "provider" => provider = Some(term_to_string(value_term)?),
+            "retry_policy" => {
+                if !(value_term.is_atom() && value_term.decode::<rustler::Atom>()? == atoms::nil())
+                {
+                    retry_policy = Some(term_to_string(value_term)?);
+                }
+            }
+            "options" => match term_to_baml_value(value_term)? {
+                BamlValue::Map(map) => options = map,
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some("options"),
+                        "Client options must be a map".to_string(),
+                    ))
+                }
+            },
+            // Shorthands for the options of fallback and round-robin clients
+            "strategy" => strategy = Some(term_to_baml_value(value_term)?),
+            "start" => start = Some(term_to_baml_value(value_term)?),
+            // The process handling the requests of "elixir" clients
+            "handler" => {
+                handler = Some(value_term.decode::<LocalPid>().map_err(|_| {
+                    invalid(
+                        None,
+                        Some("handler"),
+                        "Client handler must be a pid".to_string(),
+                    )
+                })?)
+            }
+            "timeout_ms" => match term_to_baml_value(value_term)? {
+                BamlValue::Int(n) if n > 0 => timeout = Duration::from_millis(n as u64),
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some("timeout_ms"),
+                        "Client timeout_ms must be a positive integer".to_string(),
+                    ))
+                }
+            },
+            // The canned responses of "mock" clients
+            "response" => response = Some(term_to_baml_value(value_term)?),
+            "responses" => responses = Some(term_to_baml_value(value_term)?),
+            "latency_ms" => match term_to_baml_value(value_term)? {
+                BamlValue::Int(n) if n >= 0 => latency = Some(Duration::from_millis(n as u64)),
+                _ => {
+                    return Err(invalid(
+                        None,
+                        Some("latency_ms"),
+                        "Client latency_ms must be a non-negative integer".to_string(),
+                    ))
+                }
+            },
+            _ => return Err(invalid(None, None, format!("Unknown client key: {}", key))),
+        }
+    }
+
+    let name = name.ok_or(invalid(None, None, "Client is missing a name".to_string()))?;
+    let mut provider = provider.ok_or(invalid(
+        Some(&name),
+        None,
+        format!("Client {} is missing a provider", name),
+    ))?;
+
+    let handler = match (provider.as_str(), handler) {
+        ("elixir", Some(pid)) => {
+            provider = served_api(&name, &mut options)?;
+            if Api::of(&provider).is_none() {
+                return Err(invalid(
+                    Some(&name),
+                    Some("api"),
+                    format!(
+                        "Client {} can't serve the API of {}, whose responses can't be read",
+                        name, provider
+                    ),
+                ));
+            }
+            Some(Handler { pid, timeout })
+        }
+        ("elixir", None) => {
+            return Err(invalid(
+                Some(&name),
+                Some("handler"),
+                format!("Client {} is missing a handler", name),
+            ))
+        }
+        (_, Some(_)) => {
+            return Err(invalid(
+                Some(&name),
+                Some("handler"),
+                format!(
+                    "Client {} has a handler but its provider is not elixir",
+                    name
+                ),
+            ))
+        }
+        (_, None) => None,
+    };
+
+    let mock = if provider == "mock" {
+        provider = served_api(&name, &mut options)?;
+        if !options.contains_key("model") {
+            options.insert("model".to_string(), BamlValue::String("mock".to_string()));
+        }
+        let mut mock = Mock::new(&name, latency.unwrap_or_default());
+        let invalid_responses = |key: &str, message: String| {
+            invalid(
+                Some(&name),
+                Some(key),
+                format!("Client {} has an invalid {}: {}", name, key, message),
+            )
+        };
+        if let Some(value) = response {
+            mock.set_response(value)
+                .map_err(|message| invalid_responses("response", message))?;
+        }
+        if let Some(value) = responses {
+            mock.set_responses(value)
+                .map_err(|message| invalid_responses("responses", message))?;
+        }
+        Some(Arc::new(mock))
+    } else if let Some(key) = [
+        ("response", response.is_some()),
+        ("responses", responses.is_some()),
+        ("latency_ms", latency.is_some()),
+    ]
+    .into_iter()
+    .find_map(|(key, given)| given.then_some(key))
+    {
+        return Err(invalid(
+            Some(&name),
+            Some(key),
+            format!("Client {} has {} but its provider is not mock", name, key),
+        ));
+    } else {
+        None
+    };
+
+    if let Some(strategy) = strategy {
+        options.insert("strategy".to_string(), strategy);
+    }
+    if let Some(start) = start {
+        options.insert("start".to_string(), start);
+    }
+
+    Ok(ClientDefinition {
+        name,
+        provider,
+        retry_policy,
+        options,
+        handler,
+        mock,
+    })
+}
+
+// Replaces the provider of "elixir" and "mock" clients by the API whose
+// requests BAML builds for them, given by the `api` option
+fn served_api(name: &str, options: &mut BamlMap<String, BamlValue>) -> Result<String, Error> {
+    let api = match options.shift_remove("api") {
+        Some(BamlValue::String(api)) => api,
+        None => "openai-generic".to_string(),
+        Some(_) => {
+            return Err(invalid(
+                Some(name),
+                Some("api"),
+                format!("Option api of client {} must be a string", name),
+            ))
+        }
+    };
+    // The URL is only passed on to the handler of "elixir" clients
+    if !options.contains_key("base_url") {
+        options.insert(
+            "base_url".to_string(),
+            BamlValue::String("http://localhost".to_string()),
+        );
+    }
+    Ok(api)
+}
+
+fn validate_client(client: &ClientDefinition) -> Result<(), Error> {
+    ClientProvider::from_str(&client.provider).map_err(|e| {
+        invalid(
+            Some(&client.name),
+            None,
+            format!("Client {} has an invalid provider: {}", client.name, e),
+        )
+    })?;
+    validate_options(&client.name, &client.options)?;
+
+    let is_strategy = STRATEGY_PROVIDERS.contains(&client.provider.as_str());
+    match (is_strategy, client.options.get("strategy")) {
+        (false, Some(_)) => Err(invalid(
+            Some(&client.name),
+            Some("strategy"),
+            format!(
+                "Client {} has a strategy but its provider is not fallback or round-robin",
+                client.name
+            ),
+        )),
+        (true, None) => Err(invalid(
+            Some(&client.name),
+            Some("strategy"),
+            format!("Client {} is missing a strategy", client.name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Checks the types of the options shared by most providers. Other options are
+// passed on to BAML as they are.
+fn validate_options(client: &str, options: &BamlMap<String, BamlValue>) -> Result<(), Error> {
+    for (key, value) in options {
+        // nil overrides remove an option
+        if let BamlValue::Null = value {
+            continue;
+        }
+
+        let (valid, expected) = match key.as_str() {
+            "model" | "base_url" | "api_key" | "api_version" | "resource_name"
+            | "deployment_id" | "project_id" | "location" | "region" | "default_role"
+            | "retry_policy" => (matches!(value, BamlValue::String(_)), "a string"),
+            "temperature" | "top_p" => (
+                matches!(value, BamlValue::Int(_) | BamlValue::Float(_)),
+                "a number",
+            ),
+            "max_tokens" | "max_completion_tokens" | "start" => (
+                matches!(value, BamlValue::Int(n) if *n >= 0),
+                "a non-negative integer",
+            ),
+            "headers" => (
+                matches!(value, BamlValue::Map(headers)
+                    if headers.values().all(|v| matches!(v, BamlValue::String(_)))),
+                "a map of strings",
+            ),
+            "strategy" => (
+                matches!(value, BamlValue::List(names)
+                    if !names.is_empty() && names.iter().all(|n| matches!(n, BamlValue::String(_)))),
+                "a non-empty list of client names",
+            ),
+            _ => (true, ""),
+        };
+
+        if !valid {
+            return Err(invalid(
+                Some(client),
+                Some(key),
+                format!("Option {} of client {} must be {}", key, client, expected),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_strategy_clients(
+    client: &ClientDefinition,
+    known_clients: &HashSet<&str>,
+) -> Result<(), Error> {
+    let Some(BamlValue::List(names)) = client.options.get("strategy") else {
+        return Ok(());
+    };
+
+    for name in names {
+        let BamlValue::String(name) = name else {
+            continue;
+        };
+        // "provider/model" shorthands are resolved by BAML itself
+        if !name.contains('/') && !known_clients.contains(name.as_str()) {
+            return Err(invalid(
+                Some(&client.name),
+                Some("strategy"),
+                format!(
+                    "Strategy of client {} refers to unknown client {}",
+                    client.name, name
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// A registry client for a "provider/model" shorthand like "openai/gpt-4o-mini",
+// for calls that change its options. Like the clients BAML resolves shorthands
+// to, it only sets the model and the defaults of the provider, like the
+// environment variable its API key is read from, apply to the rest.
+fn shorthand_client(name: &str) -> Option<ClientDefinition> {
+    let ClientSpec::Shorthand(provider, model) = ClientSpec::new_from_id(name).ok()? else {
+        return None;
+    };
+
+    let mut options = BamlMap::new();
+    options.insert("model".to_string(), BamlValue::String(model));
+
+    Some(ClientDefinition {
+        name: name.to_string(),
+        provider: provider.to_string(),
+        retry_policy: None,
+        options,
+        handler: None,
+        mock: None,
+    })
+}
+
+// Deep merges `overrides` into `options`, so that e.g. extra headers are added
+// to the declared ones. A nil override removes the option.
+fn merge_options(options: &mut BamlMap<String, BamlValue>, overrides: BamlMap<String, BamlValue>) {
+    for (key, value) in overrides {
+        match value {
+            BamlValue::Null => {
+                options.shift_remove(&key);
+            }
+            BamlValue::Map(value) => match options.get_mut(&key) {
+                Some(BamlValue::Map(existing)) => merge_options(existing, value),
+                _ => {
+                    options.insert(key, BamlValue::Map(value));
+                }
+            },
+            value => {
+                options.insert(key, value);
+            }
+        }
+    }
+}
+
+impl ClientDefinition {
+    fn into_property(self) -> Result<ClientProperty, Error> {
+        let provider = ClientProvider::from_str(&self.provider).map_err(|e| {
+            invalid(
+                Some(&self.name),
+                None,
+                format!("Client {} has an invalid provider: {}", self.name, e),
+            )
+        })?;
+
+        Ok(ClientProperty::new(
+            self.name,
+            provider,
+            self.retry_policy,
+            self.options,
+        ))
+    }
+}
@@ -0,0 +1,409 @@
+use baml_runtime::BamlRuntime;
+use baml_types::{BamlMap, BamlValue, EvaluationContext};
+use internal_llm_client::{ClientSpec, ResolvedClientProperty};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// What the BAML source files declare about functions and clients, read from
+// the IR of a loaded runtime, or for the options of clients from the source.
+
+// The strategy of a fallback or round-robin client
+#[derive(Clone)]
+pub enum Strategy {
+    Fallback(Vec<String>),
+    RoundRobin { clients: Vec<String>, start: usize },
+}
+
+impl Strategy {
+    pub fn clients(&self) -> &[String] {
+        match self {
+            Strategy::Fallback(clients) | Strategy::RoundRobin { clients, .. } => clients,
+        }
+    }
+}
+
+// A client declared in the BAML source files
+pub struct StaticClient {
+    pub provider: String,
+    pub retry_policy: Option<String>,
+    pub strategy: Option<Strategy>,
+}
+
+// A retry policy declared in the BAML source files, or in a client registry or
+// the runtime options, which are loaded along with them
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    delay: Duration,
+    multiplier: f64,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // How long to wait before the `retry`th retry, counted from 1
+    pub fn delay(&self, retry: usize) -> Duration {
+        let delay = self.delay.as_secs_f64() * self.multiplier.powi(retry as i32 - 1);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+// The client `function_name` is declared with, which is either the name of a
+// client or a "provider/model" shorthand.
+pub fn function_client(runtime: &BamlRuntime, function_name: &str) -> Option<String> {
+    let function = runtime
+        .inner
+        .ir
+        .walk_functions()
+        .find(|function| function.name() == function_name)?;
+    function
+        .elem()
+        .configs
+        .first()
+        .map(|config| config.client.as_str())
+}
+
+// The names of the clients declared in the BAML source files
+pub fn names(runtime: &BamlRuntime) -> HashSet<String> {
+    runtime
+        .inner
+        .ir
+        .walk_clients()
+        .map(|client| client.name().to_string())
+        .collect()
+}
+
+// The client named `name` if the BAML source files declare it
+pub fn find(runtime: &BamlRuntime, name: &str) -> Option<StaticClient> {
+    let client = runtime
+        .inner
+        .ir
+        .walk_clients()
+        .find(|client| client.name() == name)?;
+
+    // Strategies don't refer to environment variables
+    let ctx = EvaluationContext::new(&HashMap::new(), true);
+    let strategy = match client.options().resolve(&client.elem().provider, &ctx) {
+        Ok(ResolvedClientProperty::Fallback(fallback)) => Some(Strategy::Fallback(
+            fallback.strategy.iter().map(ClientSpec::as_str).collect(),
+        )),
+        Ok(ResolvedClientProperty::RoundRobin(round_robin)) => Some(Strategy::RoundRobin {
+            clients: round_robin
+                .strategy
+                .iter()
+                .map(ClientSpec::as_str)
+                .collect(),
+            start: round_robin.start_index.unwrap_or(0).max(0) as usize,
+        }),
+        _ => None,
+    };
+
+    Some(StaticClient {
+        provider: client.elem().provider.to_string(),
+        retry_policy: client.elem().retry_policy_id.clone(),
+        strategy,
+    })
+}
+
+// The environment variables the options of the client named `name` refer to,
+// including the API key its provider reads by default
+pub fn required_env_vars(runtime: &BamlRuntime, name: &str) -> HashSet<String> {
+    runtime
+        .inner
+        .ir
+        .walk_clients()
+        .find(|client| client.name() == name)
+        .map(|client| client.options().required_env_vars())
+        .unwrap_or_default()
+}
+
+// The retry policy named `name`
+pub fn retry_policy(runtime: &BamlRuntime, name: &str) -> Option<RetryPolicy> {
+    let policy = runtime
+        .inner
+        .ir
+        .walk_retry_policies()
+        .find(|policy| policy.elem().name.0 == name)?;
+
+    // The strategy is read through its serialized form, so as not to depend
+    // on the crate BAML declares retry strategies in
+    let strategy = serde_json::to_value(&policy.elem().strategy).ok()?;
+    let millis = |strategy: &Value, key: &str| {
+        Duration::from_millis(strategy.get(key).and_then(Value::as_u64).unwrap_or(0))
+    };
+    let (delay, multiplier, max_delay) = match (
+        strategy.get("ConstantDelay"),
+        strategy.get("ExponentialBackoff"),
+    ) {
+        (Some(constant), _) => (
+            millis(constant, "delay_ms"),
+            1.0,
+            millis(constant, "delay_ms"),
+        ),
+        (None, Some(exponential)) => (
+            millis(exponential, "delay_ms"),
+            exponential
+                .get("multiplier")
+                .and_then(Value::as_f64)
+                .unwrap_or(1.0),
+            millis(exponential, "max_delay_ms"),
+        ),
+        (None, None) => return None,
+    };
+
+    Some(RetryPolicy {
+        max_retries: policy.elem().max_retries as usize,
+        delay,
+        multiplier,
+        max_delay,
+    })
+}
+
+// The options the client `name` declares in the BAML source files `files`, so
+// that they can be given to a registry client standing in for it. They're read
+// from the source as they're written rather than as BAML resolves them, which
+// depends on the provider, with the environment variables they refer to read
+// from `env_vars`. Environment variables that aren't set are an error rather
+// than left out.
+pub fn options(
+    files: &HashMap<String, String>,
+    name: &str,
+    env_vars: &HashMap<String, String>,
+) -> Result<Option<BamlMap<String, BamlValue>>, String> {
+    for source in files.values() {
+        let mut parser = Parser::new(source, env_vars);
+        if let Some(options) = parser
+            .client_options(name)
+            .map_err(|e| format!("Failed to read the options of client {}: {}", name, e))?
+        {
+            return Ok(Some(options));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open(char),
+    Close(char),
+    Comma,
+    // A quoted or raw string
+    String(String),
+    // Anything else, like keywords, names, numbers and `env.NAME` references
+    Word(String),
+}
+
+// Reads the blocks of BAML source files, far enough to find the options of a
+// client. Everything outside of client blocks, like prompts and types, is
+// skipped.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    env_vars: &'a HashMap<String, String>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, env_vars: &'a HashMap<String, String>) -> Self {
+        Parser {
+            chars: source.chars().peekable(),
+            env_vars,
+        }
+    }
+
+    // The options of the client block named `name`, if the source declares
+    // it, or an empty map if it declares none
+    fn client_options(&mut self, name: &str) -> Result<Option<BamlMap<String, BamlValue>>, String> {
+        // Only the client block has to be read in full, and BAML already
+        // validated the rest
+        let mut depth = 0;
+        let mut previous = Vec::new();
+        while let Ok(Some(token)) = self.next() {
+            match &token {
+                Token::Open('{') if depth == 0 && is_client(&previous, name) => {
+                    return self.client_block().map(Some);
+                }
+                Token::Open('{') => depth += 1,
+                Token::Close('}') => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                previous.push(token);
+                if previous.len() > 2 {
+                    previous.remove(0);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn client_block(&mut self) -> Result<BamlMap<String, BamlValue>, String> {
+        let mut options = BamlMap::new();
+        loop {
+            match self.next()? {
+                Some(Token::Close('}')) => return Ok(options),
+                Some(Token::Word(key)) if key == "options" => match self.value()? {
+                    BamlValue::Map(map) => options = map,
+                    _ => return Err("options must be a map".to_string()),
+                },
+                Some(Token::Word(_)) => {
+                    self.value()?;
+                }
+                Some(token) => return Err(format!("unexpected {:?}", token)),
+                None => return Err("unexpected end of file".to_string()),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<BamlValue, String> {
+        match self.next()? {
+            Some(Token::Open('{')) => {
+                let mut map = BamlMap::new();
+                loop {
+                    let key = match self.next()? {
+                        Some(Token::Close('}')) => return Ok(BamlValue::Map(map)),
+                        Some(Token::Comma) => continue,
+                        Some(Token::Word(key) | Token::String(key)) => key,
+                        token => return Err(format!("unexpected {:?} in a map", token)),
+                    };
+                    map.insert(key, self.value()?);
+                }
+            }
+            Some(Token::Open('[')) => {
+                let mut items = Vec::new();
+                loop {
+                    match self.peek()? {
+                        Some(Token::Close(']')) => {
+                            self.next()?;
+                            return Ok(BamlValue::List(items));
+                        }
+                        Some(Token::Comma) => {
+                            self.next()?;
+                        }
+                        _ => items.push(self.value()?),
+                    }
+                }
+            }
+            Some(Token::String(s)) => Ok(BamlValue::String(s)),
+            Some(Token::Word(word)) => self.word(word),
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn word(&self, word: String) -> Result<BamlValue, String> {
+        if let Some(name) = word.strip_prefix("env.") {
+            return match self.env_vars.get(name) {
+                Some(value) => Ok(BamlValue::String(value.clone())),
+                None => Err(format!("environment variable {} is not set", name)),
+            };
+        }
+        Ok(match word.as_str() {
+            "true" => BamlValue::Bool(true),
+            "false" => BamlValue::Bool(false),
+            "null" => BamlValue::Null,
+            _ => match (word.parse::<i64>(), word.parse::<f64>()) {
+                (Ok(int), _) => BamlValue::Int(int),
+                (Err(_), Ok(float)) => BamlValue::Float(float),
+                _ => BamlValue::String(word),
+            },
+        })
+    }
+
+    fn peek(&mut self) -> Result<Option<Token>, String> {
+        let rest = self.chars.clone();
+        let token = self.next();
+        self.chars = rest;
+        token
+    }
+
+    fn next(&mut self) -> Result<Option<Token>, String> {
+        self.skip_blank();
+        let Some(c) = self.chars.next() else {
+            return Ok(None);
+        };
+        Ok(Some(match c {
+            '{' | '[' => Token::Open(c),
+            '}' | ']' => Token::Close(c),
+            ',' => Token::Comma,
+            '"' => Token::String(self.quoted()?),
+            '#' if matches!(self.chars.peek(), Some('#' | '"')) => Token::String(self.raw()?),
+            _ => {
+                let mut word = c.to_string();
+                while let Some(c) = self.chars.peek() {
+                    if c.is_whitespace() || "{}[],\"".contains(*c) {
+                        break;
+                    }
+                    word.push(*c);
+                    self.chars.next();
+                }
+                Token::Word(word)
+            }
+        }))
+    }
+
+    // Skips whitespace and `//` and `{// //}` comments
+    fn skip_blank(&mut self) {
+        loop {
+            while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let mut ahead = self.chars.clone();
+            match (ahead.next(), ahead.next(), ahead.next()) {
+                (Some('/'), Some('/'), _) => while self.chars.next_if(|c| *c != '\n').is_some() {},
+                (Some('{'), Some('/'), Some('/')) => {
+                    let mut last = ' ';
+                    for c in self.chars.by_ref().skip(3) {
+                        if last == '/' && c == '}' {
+                            break;
+                        }
+                        last = c;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        while let Some(c) = self.chars.next() {
+            match c {
+                '"' => return Ok(string),
+                '\\' => match self.chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some(c) => string.push(c),
+                    None => break,
+                },
+                _ => string.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    // A `#"..."#` string, which may be delimited by more hashes
+    fn raw(&mut self) -> Result<String, String> {
+        let mut hashes = 1;
+        while self.chars.next_if_eq(&'#').is_some() {
+            hashes += 1;
+        }
+        if self.chars.next() != Some('"') {
+            return Err("invalid raw string".to_string());
+        }
+        let end = format!("\"{}", "#".repeat(hashes));
+        let mut string = String::new();
+        for c in self.chars.by_ref() {
+            string.push(c);
+            if string.ends_with(&end) {
+                string.truncate(string.len() - end.len());
+                return Ok(string.trim().to_string());
+            }
+        }
+        Err("unterminated raw string".to_string())
+    }
+}
+
+// Whether the tokens before a block are `client Name` or `client<llm> Name`
+fn is_client(previous: &[Token], name: &str) -> bool {
+    match previous {
+        [Token::Word(keyword), Token::Word(client)] => {
+            (keyword == "client" || keyword.starts_with("client<")) && client == name
+        }
+        _ => false,
+    }
+}
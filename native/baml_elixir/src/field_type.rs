@@ -0,0 +1,304 @@
+use baml_types::{BamlMediaType, FieldType, LiteralValue, TypeValue};
+use rustler::Term;
+
+use crate::term_to_string;
+
+// Parses a BAML type expression, like `string[]`, `int?`,
+// `map<string, Person>`, `Person | Company`, `"a" | "b"` or `image`.
+// `named` resolves the names of classes, enums and type aliases, or fails for
+// unknown names.
+pub fn parse(
+    expr: &str,
+    named: &dyn Fn(&str) -> Result<FieldType, String>,
+) -> Result<FieldType, String> {
+    let mut parser = Parser {
+        chars: expr.chars().collect(),
+        position: 0,
+        named,
+    };
+    let field_type = parser.union()?;
+    parser.skip_whitespace();
+    if parser.position < parser.chars.len() {
+        return Err(format!(
+            "Expected the end of the type but {}",
+            parser.unexpected()
+        ));
+    }
+    Ok(field_type)
+}
+
+// Decodes a type given as a string to parse, or as the nested tuples
+// `parse_baml` describes types with, like `{:list, {:primitive, :string}}`.
+pub fn from_term(
+    term: Term,
+    named: &dyn Fn(&str) -> Result<FieldType, String>,
+) -> Result<FieldType, String> {
+    if let Ok(expr) = term.decode::<String>() {
+        return parse(&expr, named);
+    }
+
+    let invalid = || format!("Invalid type: {:?}", term);
+    let items = rustler::types::tuple::get_tuple(term).map_err(|_| invalid())?;
+    let (tag, args) = items.split_first().ok_or_else(invalid)?;
+    let tag = term_to_string(*tag).map_err(|_| invalid())?;
+    let string = |term: &Term| term_to_string(*term).map_err(|_| invalid());
+    let types = |term: &Term| -> Result<Vec<FieldType>, String> {
+        term.decode::<Vec<Term>>()
+            .map_err(|_| invalid())?
+            .into_iter()
+            .map(|item| from_term(item, named))
+            .collect()
+    };
+
+    match (tag.as_str(), args) {
+        ("primitive", [primitive]) => match string(primitive)?.as_str() {
+            "string" => Ok(FieldType::string()),
+            "integer" => Ok(FieldType::int()),
+            "float" => Ok(FieldType::float()),
+            "boolean" => Ok(FieldType::bool()),
+            "nil" => Ok(FieldType::Primitive(TypeValue::Null)),
+            _ => Err(invalid()),
+        },
+        ("class" | "enum", [name]) => named(&string(name)?),
+        ("alias", [name]) => named(&string(name)?),
+        ("list", [inner]) => Ok(FieldType::List(Box::new(from_term(*inner, named)?))),
+        ("optional", [inner]) => Ok(FieldType::Optional(Box::new(from_term(*inner, named)?))),
+        ("map", [key, value]) => Ok(FieldType::Map(
+            Box::new(from_term(*key, named)?),
+            Box::new(from_term(*value, named)?),
+        )),
+        ("union", [inner]) => Ok(FieldType::Union(types(inner)?)),
+        ("tuple", [inner]) => Ok(FieldType::Tuple(types(inner)?)),
+        ("literal", [value]) => {
+            if let Ok(int) = value.decode::<i64>() {
+                Ok(FieldType::Literal(LiteralValue::Int(int)))
+            } else if let Ok(boolean) = value.decode::<bool>() {
+                Ok(FieldType::Literal(LiteralValue::Bool(boolean)))
+            } else {
+                Ok(FieldType::Literal(LiteralValue::String(string(value)?)))
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    position: usize,
+    named: &'a dyn Fn(&str) -> Result<FieldType, String>,
+}
+
+impl Parser<'_> {
+    // union := postfix ("|" postfix)*
+    fn union(&mut self) -> Result<FieldType, String> {
+        let mut types = vec![self.postfix()?];
+        while self.eat('|') {
+            types.push(self.postfix()?);
+        }
+        Ok(if types.len() == 1 {
+            types.pop().unwrap()
+        } else {
+            FieldType::Union(types)
+        })
+    }
+
+    // postfix := primary ("[]" | "?")*
+    fn postfix(&mut self) -> Result<FieldType, String> {
+        let mut field_type = self.primary()?;
+        loop {
+            if self.eat('[') {
+                self.expect(']')?;
+                field_type = FieldType::List(Box::new(field_type));
+            } else if self.eat('?') {
+                field_type = FieldType::Optional(Box::new(field_type));
+            } else {
+                return Ok(field_type);
+            }
+        }
+    }
+
+    // primary := "(" union ")" | "map" "<" union "," union ">" | literal | name
+    fn primary(&mut self) -> Result<FieldType, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let field_type = self.union()?;
+                self.expect(')')?;
+                Ok(field_type)
+            }
+            Some('"') => {
+                self.position += 1;
+                let start = self.position;
+                while self.peek().is_some_and(|c| c != '"') {
+                    self.position += 1;
+                }
+                let value: String = self.chars[start..self.position].iter().collect();
+                self.expect('"')?;
+                Ok(FieldType::Literal(LiteralValue::String(value)))
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.position;
+                self.position += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                    self.position += 1;
+                }
+                let digits: String = self.chars[start..self.position].iter().collect();
+                digits
+                    .parse()
+                    .map(|int| FieldType::Literal(LiteralValue::Int(int)))
+                    .map_err(|_| format!("Invalid integer literal {}", digits))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    self.position += 1;
+                }
+                let name: String = self.chars[start..self.position].iter().collect();
+                self.named_type(&name)
+            }
+            _ => Err(format!("Expected a type but {}", self.unexpected())),
+        }
+    }
+
+    fn named_type(&mut self, name: &str) -> Result<FieldType, String> {
+        Ok(match name {
+            "string" => FieldType::string(),
+            "int" => FieldType::int(),
+            "float" => FieldType::float(),
+            "bool" => FieldType::bool(),
+            "null" => FieldType::Primitive(TypeValue::Null),
+            "true" => FieldType::Literal(LiteralValue::Bool(true)),
+            "false" => FieldType::Literal(LiteralValue::Bool(false)),
+            "image" => FieldType::Primitive(TypeValue::Media(BamlMediaType::Image)),
+            "audio" => FieldType::Primitive(TypeValue::Media(BamlMediaType::Audio)),
+            "map" => {
+                self.expect('<')?;
+                let key = self.union()?;
+                self.expect(',')?;
+                let value = self.union()?;
+                self.expect('>')?;
+                FieldType::Map(Box::new(key), Box::new(value))
+            }
+            _ => (self.named)(name)?,
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("Expected {} but {}", c, self.unexpected()))
+        }
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("found {} at position {}", c, self.position),
+            None => "the type ended".to_string(),
+        }
+    }
+}
+
+// Writes a type as a BAML type expression. Checks, asserts and streaming
+// attributes are left out, as they don't change how the type is described to
+// the model.
+pub fn to_baml(field_type: &FieldType) -> String {
+    // Unions are put in parentheses when a list or an optional wraps them
+    let nested = |inner: &FieldType| match inner {
+        FieldType::Union(_) => format!("({})", to_baml(inner)),
+        _ => to_baml(inner),
+    };
+    match field_type {
+        FieldType::Primitive(TypeValue::String) => "string".to_string(),
+        FieldType::Primitive(TypeValue::Int) => "int".to_string(),
+        FieldType::Primitive(TypeValue::Float) => "float".to_string(),
+        FieldType::Primitive(TypeValue::Bool) => "bool".to_string(),
+        FieldType::Primitive(TypeValue::Null) => "null".to_string(),
+        FieldType::Primitive(TypeValue::Media(BamlMediaType::Image)) => "image".to_string(),
+        FieldType::Primitive(TypeValue::Media(BamlMediaType::Audio)) => "audio".to_string(),
+        FieldType::Literal(LiteralValue::String(value)) => format!("\"{}\"", value),
+        FieldType::Literal(LiteralValue::Int(value)) => value.to_string(),
+        FieldType::Literal(LiteralValue::Bool(value)) => value.to_string(),
+        FieldType::Class(name) | FieldType::Enum(name) | FieldType::RecursiveTypeAlias(name) => {
+            name.clone()
+        }
+        FieldType::List(inner) => format!("{}[]", nested(inner)),
+        FieldType::Optional(inner) => format!("{}?", nested(inner)),
+        FieldType::Map(key, value) => format!("map<{}, {}>", to_baml(key), to_baml(value)),
+        FieldType::Union(types) => types.iter().map(to_baml).collect::<Vec<_>>().join(" | "),
+        FieldType::WithMetadata { base, .. } => to_baml(base),
+        _ => field_type.to_string(),
+    }
+}
+
+// Calls `visit` with `field_type` and each of the types nested in it
+pub fn walk(field_type: &FieldType, visit: &mut dyn FnMut(&FieldType)) {
+    visit(field_type);
+    match field_type {
+        FieldType::List(inner) | FieldType::Optional(inner) => walk(inner, visit),
+        FieldType::Map(key, value) => {
+            walk(key, visit);
+            walk(value, visit);
+        }
+        FieldType::Union(types) | FieldType::Tuple(types) => {
+            for inner in types {
+                walk(inner, visit);
+            }
+        }
+        FieldType::WithMetadata { base, .. } => walk(base, visit),
+        _ => {}
+    }
+}
+
+// Replaces the references to type aliases `resolve` returns a type for with
+// that type
+pub fn replace_aliases(
+    field_type: &FieldType,
+    resolve: &dyn Fn(&str) -> Option<FieldType>,
+) -> FieldType {
+    let replace = |inner: &FieldType| Box::new(replace_aliases(inner, resolve));
+    let replace_all =
+        |types: &[FieldType]| types.iter().map(|t| replace_aliases(t, resolve)).collect();
+    match field_type {
+        FieldType::RecursiveTypeAlias(name) => resolve(name).unwrap_or_else(|| field_type.clone()),
+        FieldType::List(inner) => FieldType::List(replace(inner)),
+        FieldType::Optional(inner) => FieldType::Optional(replace(inner)),
+        FieldType::Map(key, value) => FieldType::Map(replace(key), replace(value)),
+        FieldType::Union(types) => FieldType::Union(replace_all(types)),
+        FieldType::Tuple(types) => FieldType::Tuple(replace_all(types)),
+        FieldType::WithMetadata {
+            base,
+            constraints,
+            streaming_behavior,
+        } => FieldType::WithMetadata {
+            base: replace(base),
+            constraints: constraints.clone(),
+            streaming_behavior: streaming_behavior.clone(),
+        },
+        _ => field_type.clone(),
+    }
+}
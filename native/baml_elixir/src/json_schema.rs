@@ -0,0 +1,467 @@
+use rustler::{Encoder, Env, Error, NifMap, NifResult, Term};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::atoms;
+
+// Returned as `{:error, %{kind: :invalid_json_schema, ...}}` when a JSON
+// Schema can't be converted. `path` is the JSON pointer of the offending
+// schema, like `#/properties/address`.
+#[derive(NifMap)]
+struct InvalidJsonSchema {
+    kind: rustler::Atom,
+    path: String,
+    message: String,
+}
+
+fn invalid(path: &str, message: String) -> Error {
+    Error::Term(Box::new(InvalidJsonSchema {
+        kind: atoms::invalid_json_schema(),
+        path: path.to_string(),
+        message,
+    }))
+}
+
+// An entry of a type builder, as `type_builder::from_term` decodes them
+pub enum Definition {
+    Class {
+        name: String,
+        description: Option<String>,
+        properties: Vec<Property>,
+    },
+    Enum {
+        name: String,
+        values: Vec<String>,
+    },
+    Alias {
+        name: String,
+        target: String,
+    },
+}
+
+pub struct Property {
+    name: String,
+    // The name of the property in the schema, when it isn't a valid BAML name
+    alias: Option<String>,
+    // A BAML type expression
+    r#type: String,
+    description: Option<String>,
+}
+
+impl Definition {
+    pub fn encode<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let atom = |name: &str| rustler::Atom::from_str(env, name);
+        match self {
+            Definition::Class {
+                name,
+                description,
+                properties,
+            } => {
+                let properties = properties
+                    .iter()
+                    .map(|property| property.encode(env))
+                    .collect::<NifResult<Vec<_>>>()?;
+                match description {
+                    Some(description) => {
+                        let attributes = Term::map_new(env)
+                            .map_put(atom("description")?.encode(env), description.encode(env))?;
+                        Ok((atom("class")?, name, properties, attributes).encode(env))
+                    }
+                    None => Ok((atom("class")?, name, properties).encode(env)),
+                }
+            }
+            Definition::Enum { name, values } => Ok((atom("enum")?, name, values).encode(env)),
+            Definition::Alias { name, target } => Ok((atom("alias")?, name, target).encode(env)),
+        }
+    }
+}
+
+impl Property {
+    fn encode<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let mut map = Term::map_new(env);
+        let mut put = |key: &str, value: &str| -> NifResult<()> {
+            map = map.map_put(
+                rustler::Atom::from_str(env, key)?.encode(env),
+                value.encode(env),
+            )?;
+            Ok(())
+        };
+        put("name", &self.name)?;
+        put("type", &self.r#type)?;
+        if let Some(alias) = &self.alias {
+            put("alias", alias)?;
+        }
+        if let Some(description) = &self.description {
+            put("description", description)?;
+        }
+        Ok(map)
+    }
+}
+
+// Converts a JSON Schema into type builder entries, with the root schema
+// named `name`, or after its `title`.
+//
+// Objects with properties become classes, whose properties are optional
+// unless `required`, and string enums become enums, or unions of literals
+// when their values aren't valid BAML names. `oneOf`, `anyOf` and lists of
+// types become unions, and `$ref`s to `$defs` or `definitions` become
+// classes, enums or type aliases named after the definition, which may refer
+// to themselves. Schemas that allow any value use a `JsonValue` type alias.
+pub fn to_definitions(schema: &Value, name: Option<String>) -> Result<Vec<Definition>, Error> {
+    let name = match name {
+        Some(name) => name,
+        None => schema
+            .get("title")
+            .and_then(Value::as_str)
+            .map(pascal_case)
+            .ok_or_else(|| {
+                invalid(
+                    "#",
+                    "The schema needs a title, or a name for its root type".to_string(),
+                )
+            })?,
+    };
+
+    let mut converter = Converter {
+        root: schema,
+        definitions: Vec::new(),
+        names: HashSet::from([name.clone()]),
+        refs: HashMap::from([("#".to_string(), name.clone())]),
+        json_value: None,
+    };
+    converter.named_type(schema, "#", &name)?;
+    Ok(converter.definitions)
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    definitions: Vec<Definition>,
+    // The names given to types so far
+    names: HashSet<String>,
+    // The names of the types `$ref`s point at
+    refs: HashMap<String, String>,
+    json_value: Option<String>,
+}
+
+impl Converter<'_> {
+    // Defines the type of a schema under `name`, as a class, an enum or a
+    // type alias
+    fn named_type(&mut self, schema: &Value, path: &str, name: &str) -> Result<(), Error> {
+        if is_object(schema) {
+            return self.class(schema, path, name);
+        }
+        if let Some(values) = enum_values(schema) {
+            self.definitions.push(Definition::Enum {
+                name: name.to_string(),
+                values,
+            });
+            return Ok(());
+        }
+        let target = self.type_of(schema, path, name)?;
+        self.definitions.push(Definition::Alias {
+            name: name.to_string(),
+            target,
+        });
+        Ok(())
+    }
+
+    // Returns the BAML type expression of a schema, defining the classes and
+    // enums it needs, which are named after their `title` or after `hint`
+    fn type_of(&mut self, schema: &Value, path: &str, hint: &str) -> Result<String, Error> {
+        let object = match schema {
+            Value::Bool(true) => return Ok(self.json_value()),
+            Value::Object(object) => object,
+            _ => return Err(invalid(path, "Expected a schema".to_string())),
+        };
+
+        let field_type = if let Some(reference) = object.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| invalid(path, "$ref must be a string".to_string()))?;
+            self.reference(reference, path)?
+        } else if let Some(value) = object.get("const") {
+            literal(value, path)?
+        } else if let Some(values) = object.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| invalid(path, "enum must be a list".to_string()))?;
+            if enum_values(schema).is_some() {
+                let name = self.unique_name(&title_or(schema, hint));
+                self.named_type(schema, path, &name)?;
+                name
+            } else {
+                values
+                    .iter()
+                    .map(|value| literal(value, path))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(" | ")
+            }
+        } else if let Some((keyword, schemas)) = ["oneOf", "anyOf"]
+            .iter()
+            .find_map(|keyword| object.get(*keyword).map(|schemas| (*keyword, schemas)))
+        {
+            let schemas = schemas
+                .as_array()
+                .filter(|schemas| !schemas.is_empty())
+                .ok_or_else(|| invalid(path, format!("{} must be a non-empty list", keyword)))?;
+            schemas
+                .iter()
+                .enumerate()
+                .map(|(index, schema)| {
+                    let path = format!("{}/{}/{}", path, keyword, index);
+                    let hint = format!("{}{}", hint, index + 1);
+                    self.type_of(schema, &path, &hint).map(|t| group(&t))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join(" | ")
+        } else if let Some(schemas) = object.get("allOf") {
+            match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => self.type_of(schema, &format!("{}/allOf/0", path), hint)?,
+                _ => {
+                    return Err(invalid(
+                        path,
+                        "allOf is only supported with a single schema".to_string(),
+                    ))
+                }
+            }
+        } else {
+            match object.get("type") {
+                Some(Value::String(name)) => self.simple_type(schema, name, path, hint)?,
+                Some(Value::Array(names)) => names
+                    .iter()
+                    .map(|name| match name.as_str() {
+                        Some(name) => self.simple_type(schema, name, path, hint),
+                        None => Err(invalid(path, "type must be a string".to_string())),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(" | "),
+                Some(_) => return Err(invalid(path, "type must be a string".to_string())),
+                None if object.contains_key("properties") => {
+                    self.simple_type(schema, "object", path, hint)?
+                }
+                None if object.contains_key("items") => {
+                    self.simple_type(schema, "array", path, hint)?
+                }
+                None => self.json_value(),
+            }
+        };
+
+        if object.get("nullable") == Some(&Value::Bool(true)) {
+            Ok(format!("{}?", group(&field_type)))
+        } else {
+            Ok(field_type)
+        }
+    }
+
+    fn simple_type(
+        &mut self,
+        schema: &Value,
+        name: &str,
+        path: &str,
+        hint: &str,
+    ) -> Result<String, Error> {
+        match name {
+            "string" => Ok("string".to_string()),
+            "integer" => Ok("int".to_string()),
+            "number" => Ok("float".to_string()),
+            "boolean" => Ok("bool".to_string()),
+            "null" => Ok("null".to_string()),
+            "array" => match schema.get("items") {
+                Some(items @ (Value::Object(_) | Value::Bool(true))) => {
+                    let path = format!("{}/items", path);
+                    Ok(format!("{}[]", group(&self.type_of(items, &path, hint)?)))
+                }
+                Some(_) => Err(invalid(path, "items must be a schema".to_string())),
+                None => Ok(format!("{}[]", self.json_value())),
+            },
+            "object" if has_properties(schema) => {
+                let name = self.unique_name(&title_or(schema, hint));
+                self.class(schema, path, &name)?;
+                Ok(name)
+            }
+            "object" => match schema.get("additionalProperties") {
+                Some(values @ Value::Object(_)) => {
+                    let path = format!("{}/additionalProperties", path);
+                    let values = self.type_of(values, &path, hint)?;
+                    Ok(format!("map<string, {}>", values))
+                }
+                _ => Ok(format!("map<string, {}>", self.json_value())),
+            },
+            _ => Err(invalid(path, format!("Unknown type {}", name))),
+        }
+    }
+
+    fn class(&mut self, schema: &Value, path: &str, name: &str) -> Result<(), Error> {
+        let required: HashSet<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|names| names.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut properties = Vec::new();
+        if let Some(Value::Object(schemas)) = schema.get("properties") {
+            for (key, property) in schemas {
+                let path = format!("{}/properties/{}", path, key);
+                let hint = format!("{}{}", name, pascal_case(key));
+                let mut field_type = self.type_of(property, &path, &hint)?;
+                if !required.contains(key.as_str()) && !field_type.ends_with('?') {
+                    field_type = format!("{}?", group(&field_type));
+                }
+                let property_name = identifier(key);
+                properties.push(Property {
+                    alias: (property_name != *key).then(|| key.clone()),
+                    name: property_name,
+                    r#type: field_type,
+                    description: description(property),
+                });
+            }
+        }
+
+        self.definitions.push(Definition::Class {
+            name: name.to_string(),
+            description: description(schema),
+            properties,
+        });
+        Ok(())
+    }
+
+    fn reference(&mut self, reference: &str, path: &str) -> Result<String, Error> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let (definitions, key) = ["$defs", "definitions"]
+            .iter()
+            .find_map(|definitions| {
+                reference
+                    .strip_prefix(&format!("#/{}/", definitions))
+                    .map(|key| (*definitions, key))
+            })
+            .ok_or_else(|| invalid(path, format!("Unsupported $ref {}", reference)))?;
+        let root = self.root;
+        let schema = root
+            .get(definitions)
+            .and_then(|definitions| definitions.get(key))
+            .ok_or_else(|| invalid(path, format!("$ref {} points at nothing", reference)))?;
+
+        // Named before it's converted, so that it can refer to itself
+        let name = self.unique_name(&pascal_case(key));
+        self.refs.insert(reference.to_string(), name.clone());
+        self.named_type(schema, reference, &name)?;
+        Ok(name)
+    }
+
+    fn json_value(&mut self) -> String {
+        if let Some(name) = &self.json_value {
+            return name.clone();
+        }
+        let name = self.unique_name("JsonValue");
+        self.definitions.push(Definition::Alias {
+            name: name.clone(),
+            target: format!(
+                "int | float | bool | string | null | {0}[] | map<string, {0}>",
+                name
+            ),
+        });
+        self.json_value = Some(name.clone());
+        name
+    }
+
+    fn unique_name(&mut self, name: &str) -> String {
+        let mut unique = name.to_string();
+        let mut count = 1;
+        while !self.names.insert(unique.clone()) {
+            count += 1;
+            unique = format!("{}{}", name, count);
+        }
+        unique
+    }
+}
+
+// Whether a schema is an object with properties, which becomes a class
+fn is_object(schema: &Value) -> bool {
+    let object_type = match schema.get("type") {
+        None => true,
+        Some(name) => name == "object",
+    };
+    object_type && has_properties(schema)
+}
+
+fn has_properties(schema: &Value) -> bool {
+    schema.get("properties").is_some_and(Value::is_object)
+}
+
+// The values of a string enum, when they are all valid BAML names
+fn enum_values(schema: &Value) -> Option<Vec<String>> {
+    let values = schema.get("enum")?.as_array()?;
+    let values: Vec<String> = values
+        .iter()
+        .map(|value| value.as_str().map(str::to_string))
+        .collect::<Option<_>>()?;
+    let valid = !values.is_empty() && values.iter().all(|value| identifier(value) == *value);
+    valid.then_some(values)
+}
+
+fn literal(value: &Value, path: &str) -> Result<String, Error> {
+    match value {
+        Value::String(value) if !value.contains('"') => Ok(format!("\"{}\"", value)),
+        Value::Number(number) if number.is_i64() => Ok(number.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Null => Ok("null".to_string()),
+        _ => Err(invalid(path, format!("Unsupported literal {}", value))),
+    }
+}
+
+fn description(schema: &Value) -> Option<String> {
+    schema
+        .get("description")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+fn title_or(schema: &Value, hint: &str) -> String {
+    schema
+        .get("title")
+        .and_then(Value::as_str)
+        .map(pascal_case)
+        .unwrap_or_else(|| hint.to_string())
+}
+
+// Parenthesizes unions, so that they can be made optional or a list
+fn group(field_type: &str) -> String {
+    if field_type.contains('|') {
+        format!("({})", field_type)
+    } else {
+        field_type.to_string()
+    }
+}
+
+// A valid BAML name for a property, like `first_name` for `first-name`
+fn identifier(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => name,
+        _ => format!("_{}", name),
+    }
+}
+
+// A valid BAML name for a type, like `PostalAddress` for `postal address`
+fn pascal_case(name: &str) -> String {
+    let name: String = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+        })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("Type{}", name),
+    }
+}
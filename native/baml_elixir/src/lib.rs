@@ -4,11 +4,12 @@ use baml_runtime::type_builder::TypeBuilder;
 use baml_runtime::{BamlRuntime, FunctionResult, RuntimeContextManager};
 use baml_types::{BamlMap, BamlValue, FieldType, LiteralValue};
 use collector::{FunctionLog, Usage};
+use rustler::env::SavedTerm;
 use rustler::{
-    Encoder, Env, Error, ListIterator, LocalPid, MapIterator, NifResult, NifStruct, ResourceArc,
-    Term,
+    Encoder, Env, Error, LocalPid, MapIterator, NifResult, NifStruct, OwnedEnv, ResourceArc, Term,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
 use std::sync::Arc;
 mod atoms {
@@ -18,10 +19,42 @@ mod atoms {
         nil,
         partial,
         done,
+        invalid_client_registry,
+        invalid_type_builder,
+        invalid_json_schema,
+        queue_timeout,
+        circuit_open,
+        closed,
+        open,
+        half_open,
+        state,
+        consecutive_failures,
+        error_rate,
+        retry_after_ms,
+        baml_elixir_secret,
+        secret_unavailable,
+        baml_elixir_request,
+        chunk,
     }
 }
 
+mod cassette;
+mod circuit_breaker;
+mod client_registry;
+mod clients;
 mod collector;
+mod field_type;
+mod json_schema;
+mod limits;
+mod mock;
+mod orchestrator;
+mod output_format;
+mod pool;
+mod reply;
+mod runtime;
+mod secrets;
+mod transport;
+mod type_builder;
 
 fn term_to_string(term: Term) -> Result<String, Error> {
     if term.is_atom() {
@@ -65,6 +98,10 @@ fn term_to_baml_value<'a>(term: Term<'a>) -> Result<BamlValue, Error> {
         return Ok(BamlValue::Map(map));
     }
 
+    if let Ok(boolean) = term.decode::<bool>() {
+        return Ok(BamlValue::Bool(boolean));
+    }
+
     if term.is_atom() && term.decode::<rustler::Atom>()? == atoms::nil() {
         return Ok(BamlValue::Null);
     }
@@ -75,7 +112,27 @@ fn term_to_baml_value<'a>(term: Term<'a>) -> Result<BamlValue, Error> {
     ))))
 }
 
-fn baml_value_to_term<'a>(env: Env<'a>, value: &BamlValue) -> NifResult<Term<'a>> {
+// The fields declared in the BAML source files of each @@dynamic class
+type DynamicClasses = HashMap<String, HashSet<String>>;
+
+fn dynamic_classes(runtime: &BamlRuntime) -> DynamicClasses {
+    runtime
+        .inner
+        .ir
+        .walk_classes()
+        .filter(|class| class.item.attributes.get("dynamic_type").is_some())
+        .map(|class| {
+            let fields = class.walk_fields().map(|f| f.name().to_string()).collect();
+            (class.name().to_string(), fields)
+        })
+        .collect()
+}
+
+fn baml_value_to_term<'a>(
+    env: Env<'a>,
+    value: &BamlValue,
+    dynamic_classes: &DynamicClasses,
+) -> NifResult<Term<'a>> {
     match value {
         BamlValue::String(s) => Ok(s.encode(env)),
         BamlValue::Int(i) => Ok(i.encode(env)),
@@ -85,14 +142,14 @@ fn baml_value_to_term<'a>(env: Env<'a>, value: &BamlValue) -> NifResult<Term<'a>
         BamlValue::List(items) => {
             let terms: Result<Vec<Term>, Error> = items
                 .iter()
-                .map(|item| baml_value_to_term(env, item))
+                .map(|item| baml_value_to_term(env, item, dynamic_classes))
                 .collect();
             Ok(terms?.encode(env))
         }
         BamlValue::Map(map) => {
             let mut result_map = Term::map_new(env);
             for (key, value) in map.iter() {
-                let value_term = baml_value_to_term(env, value)?;
+                let value_term = baml_value_to_term(env, value, dynamic_classes)?;
                 result_map = result_map
                     .map_put(key.encode(env), value_term)
                     .map_err(|_| Error::Term(Box::new("Failed to add key to map")))?;
@@ -106,13 +163,34 @@ fn baml_value_to_term<'a>(env: Env<'a>, value: &BamlValue) -> NifResult<Term<'a>
             result_map = result_map
                 .map_put(class_atom.encode(env), class_name.encode(env))
                 .map_err(|_| Error::Term(Box::new("Failed to add class name")))?;
+
+            // Fields of @@dynamic classes that aren't declared in the BAML
+            // source files go under `__dynamic__`
+            let static_fields = dynamic_classes.get(class_name);
+            let mut dynamic_map = Term::map_new(env);
             for (key, value) in map.iter() {
                 let key_atom = rustler::Atom::from_str(env, key)
                     .map_err(|_| Error::Term(Box::new("Failed to create key atom")))?;
-                let value_term = baml_value_to_term(env, value)?;
+                let value_term = baml_value_to_term(env, value, dynamic_classes)?;
+                match static_fields {
+                    Some(fields) if !fields.contains(key) => {
+                        dynamic_map = dynamic_map
+                            .map_put(key_atom.encode(env), value_term)
+                            .map_err(|_| Error::Term(Box::new("Failed to add key to map")))?;
+                    }
+                    _ => {
+                        result_map = result_map
+                            .map_put(key_atom.encode(env), value_term)
+                            .map_err(|_| Error::Term(Box::new("Failed to add key to map")))?;
+                    }
+                }
+            }
+            if static_fields.is_some() {
+                let dynamic_atom = rustler::Atom::from_str(env, "__dynamic__")
+                    .map_err(|_| Error::Term(Box::new("Failed to create atom")))?;
                 result_map = result_map
-                    .map_put(key_atom.encode(env), value_term)
-                    .map_err(|_| Error::Term(Box::new("Failed to add key to map")))?;
+                    .map_put(dynamic_atom.encode(env), dynamic_map)
+                    .map_err(|_| Error::Term(Box::new("Failed to add dynamic fields")))?;
             }
             Ok(result_map)
         }
@@ -146,26 +224,69 @@ struct Client<'a> {
     collectors: Vec<ResourceArc<collector::CollectorResource>>,
 }
 
-fn prepare_request<'a>(
+// A call or a stream decoded from the NIF arguments, prepared into a Request
+// on the thread it runs on.
+struct Call {
+    function_name: String,
+    resource: Option<ResourceArc<runtime::RuntimeResource>>,
+    runtime: Arc<BamlRuntime>,
+    // The source files the runtime was loaded from
+    files: Arc<HashMap<String, String>>,
+    params: BamlMap<String, BamlValue>,
+    collectors: Vec<Arc<Collector>>,
+    client_registry: Option<client_registry::Spec>,
+    tb: Option<TypeBuilder>,
+}
+
+// Everything a call or a stream needs to run.
+struct Request {
+    function_name: String,
+    runtime: Arc<BamlRuntime>,
+    params: BamlMap<String, BamlValue>,
+    ctx: RuntimeContextManager,
+    collectors: Option<Vec<Arc<Collector>>>,
+    client_registry: Option<ClientRegistry>,
+    tb: Option<TypeBuilder>,
+    env_vars: HashMap<String, String>,
+    // Set when the requests of the call aren't all sent by BAML, or must pass
+    // the limits and circuit breaker of the runtime resource
+    orchestration: Option<orchestrator::Orchestration>,
+    cancellation: Arc<pool::Cancellation>,
+}
+
+fn decode_call<'a>(
+    function_name: String,
     args: Term<'a>,
-    path: String,
+    runtime: Term<'a>,
     collectors: Vec<ResourceArc<collector::CollectorResource>>,
     client_registry: Term<'a>,
     tb_elixir: Term<'a>,
-) -> Result<
-    (
-        BamlRuntime,
-        BamlMap<String, BamlValue>,
-        RuntimeContextManager,
-        Option<Vec<Arc<Collector>>>,
-        Option<ClientRegistry>,
-        Option<TypeBuilder>,
-    ),
-    Error,
-> {
-    let runtime = match BamlRuntime::from_directory(&Path::new(&path), std::env::vars().collect()) {
-        Ok(r) => r,
-        Err(e) => return Err(Error::Term(Box::new(e.to_string()))),
+) -> Result<Call, Error> {
+    let client_registry = client_registry::Spec::from_term(client_registry)?;
+
+    // `runtime` is either the path to the BAML source files, loaded for the
+    // call along with the retry policies of the registry, or a runtime resource
+    let resource = runtime
+        .decode::<ResourceArc<runtime::RuntimeResource>>()
+        .ok();
+    let (runtime, files) = match &resource {
+        Some(resource) => {
+            if let Some(spec) = &client_registry {
+                spec.check_retry_policies(&resource.retry_policies)?;
+            }
+            (resource.runtime.clone(), resource.files.clone())
+        }
+        None => {
+            let retry_policies = client_registry
+                .as_ref()
+                .and_then(|spec| spec.retry_policies_source());
+            let (runtime, files) = runtime::load(
+                &runtime.decode::<String>()?,
+                std::env::vars().collect(),
+                retry_policies,
+            )?;
+            (Arc::new(runtime), Arc::new(files))
+        }
     };
 
     // Convert args to BamlMap
@@ -181,226 +302,313 @@ fn prepare_request<'a>(
         return Err(Error::Term(Box::new("Arguments must be a map")));
     }
 
+    let tb = type_builder::from_term(tb_elixir, &runtime)?;
+
+    Ok(Call {
+        function_name,
+        resource,
+        runtime,
+        files,
+        params,
+        collectors: collectors.iter().map(|c| c.inner.clone()).collect(),
+        client_registry,
+        tb,
+    })
+}
+
+// Resolves the secrets of the call and builds its client registry, which may
+// wait on Elixir processes
+fn prepare_request(call: Call, cancellation: Arc<pool::Cancellation>) -> Result<Request, Error> {
+    let Call {
+        function_name,
+        resource,
+        runtime,
+        files,
+        params,
+        collectors,
+        client_registry,
+        tb,
+    } = call;
+
+    let mut env_vars: HashMap<String, String> = std::env::vars().collect();
+    if let Some(secrets) = resource.as_ref().and_then(|r| r.secrets.as_ref()) {
+        let names = client_registry
+            .clone()
+            .unwrap_or_default()
+            .required_env_vars(&function_name, &runtime);
+        secrets.resolve(&names, &mut env_vars)?;
+    }
+
     // Create context
     let ctx = runtime.create_ctx_manager(
         BamlValue::String("elixir".to_string()),
         None, // baml source reader
     );
 
+    let orchestration = orchestrator::Orchestration::new(
+        client_registry.as_ref(),
+        &function_name,
+        &runtime,
+        resource.as_ref(),
+        !collectors.is_empty(),
+    )?;
     let collectors = if collectors.is_empty() {
         None
     } else {
-        Some(collectors.iter().map(|c| c.inner.clone()).collect())
+        Some(collectors)
     };
 
-    let client_registry = if client_registry.is_atom()
-        && client_registry.decode::<rustler::Atom>()? == atoms::nil()
-    {
-        None
-    } else if client_registry.is_map() {
-        let mut registry = ClientRegistry::new();
-        let iter = MapIterator::new(client_registry)
-            .ok_or(Error::Term(Box::new("Invalid registry map")))?;
-        for (key_term, value_term) in iter {
-            let key = term_to_string(key_term)?;
-            if key == "primary" {
-                let primary = term_to_string(value_term)?;
-                registry.set_primary(primary);
-            }
-        }
-        Some(registry)
-    } else {
-        return Err(Error::Term(Box::new(
-            "Client registry must be nil or a map",
-        )));
+    // The orchestrator retries clients itself, so BAML must not retry the
+    // requests it makes for it
+    let client_registry = match (client_registry, &orchestration) {
+        (spec, Some(_)) => Some(spec.unwrap_or_default().build(
+            &function_name,
+            &runtime,
+            &files,
+            &env_vars,
+            true,
+        )?),
+        (Some(spec), None) => {
+            Some(spec.build(&function_name, &runtime, &files, &env_vars, false)?)
+        }
+        (None, None) => None,
     };
 
-    let tb = if tb_elixir.is_list() {
-        let builder = TypeBuilder::new();
+    Ok(Request {
+        function_name,
+        runtime,
+        params,
+        ctx,
+        collectors,
+        client_registry,
+        tb,
+        env_vars,
+        orchestration,
+        cancellation,
+    })
+}
 
-        let iter: ListIterator = tb_elixir.decode()?;
+// The value of a function result, or why it has none
+fn function_value(result: &FunctionResult) -> Result<BamlValue, String> {
+    match result.parsed() {
+        Some(Ok(response_baml_value)) => Ok(response_baml_value.0.clone().value()),
+        Some(Err(e)) => Err(format!("{:?}", e)),
+        None => Err("No parsed value available".to_string()),
+    }
+}
 
-        // Iterate over each item in the list
-        for item_term in iter {
-            // Each item is a tuple in the format {:class, "Person", fields}
-            // We need to decode it as a tuple
-            if let Ok((kind, name, fields)) =
-                item_term.decode::<(rustler::Atom, String, Vec<Term>)>()
-            {
-                let env = item_term.get_env();
-                let class_atom = rustler::Atom::from_str(env, "class")
-                    .map_err(|_| Error::Term(Box::new("Failed to create atom")))?;
+// Runs the call of `request`, through the orchestrator if it has one. With
+// `on_partial`, the call is streamed and `on_partial` is called with each
+// partial result.
+fn run(request: &Request, on_partial: Option<&dyn Fn(BamlValue)>) -> Result<BamlValue, Error> {
+    match &request.orchestration {
+        Some(orchestration) => orchestrator::run(orchestration, request, on_partial),
+        None => run_baml(request, request.client_registry.as_ref(), None, on_partial)
+            .map_err(|e| Error::Term(Box::new(e))),
+    }
+}
 
-                if kind == class_atom {
-                    let cls = builder.class(&name);
-                    let cls = cls.lock().unwrap();
-
-                    // Iterate over the fields list
-                    for field_term in fields {
-                        // Each field is a map with name, type, description
-                        if field_term.is_map() {
-                            let field_iter = MapIterator::new(field_term)
-                                .ok_or(Error::Term(Box::new("Invalid field map")))?;
-
-                            let mut field_name = String::new();
-                            let mut field_type = String::new();
-
-                            for (key_term, value_term) in field_iter {
-                                let key = term_to_string(key_term)?;
-                                match key.as_str() {
-                                    "name" => field_name = term_to_string(value_term)?,
-                                    "type" => field_type = term_to_string(value_term)?,
-                                    _ => {} // Ignore other fields like description
-                                }
-                            }
-
-                            // Set the field type based on the string type
-                            let property = cls.property(&field_name);
-                            let property = property.lock().unwrap();
-                            match field_type.as_str() {
-                                "string" => property.r#type(FieldType::string()),
-                                "int" => property.r#type(FieldType::int()),
-                                "float" => property.r#type(FieldType::float()),
-                                "bool" => property.r#type(FieldType::bool()),
-                                // TODO: handle other types and metadata as well.
-                                _ => property.r#type(FieldType::class(&field_type)),
-                            };
-                        }
-                    }
-                }
-            }
-        }
+// Has BAML make the call of `request` with `registry`, logged to `collector`
+// on top of the collectors of the call
+fn run_baml(
+    request: &Request,
+    registry: Option<&ClientRegistry>,
+    collector: Option<Arc<Collector>>,
+    on_partial: Option<&dyn Fn(BamlValue)>,
+) -> Result<BamlValue, String> {
+    let mut collectors = request.collectors.clone();
+    if let Some(collector) = collector {
+        collectors.get_or_insert_with(Vec::new).push(collector);
+    }
 
-        Some(builder)
-    } else {
-        None
+    let Some(on_partial) = on_partial else {
+        let (result, _trace_id) = request.runtime.call_function_sync(
+            request.function_name.clone(),
+            &request.params,
+            &request.ctx,
+            request.tb.as_ref(), // type builder (optional)
+            registry,            // client registry (optional)
+            collectors,
+            request.env_vars.clone(),
+        );
+        return result
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|result| function_value(&result));
+    };
+
+    let on_event = |r: FunctionResult| {
+        // Partial results that can't be coerced to a BAML value yet, because
+        // they're incomplete, are skipped. A real error shows in the final
+        // result.
+        if let Ok(value) = function_value(&r) {
+            on_partial(value);
+        }
     };
 
-    Ok((runtime, params, ctx, collectors, client_registry, tb))
+    let mut stream = request
+        .runtime
+        .stream_function(
+            request.function_name.clone(),
+            &request.params,
+            &request.ctx,
+            request.tb.as_ref(),
+            registry,
+            collectors,
+            request.env_vars.clone(),
+        )
+        .map_err(|e| format!("{:?}", e))?;
+    let (result, _trace_id) = stream.run_sync(
+        Some(on_event),
+        &request.ctx,
+        None,
+        None,
+        request.env_vars.clone(),
+    );
+    result
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|result| function_value(&result))
+}
+
+// The process a call runs for, which gets `{reference, message}` messages
+// until the call is cancelled
+struct Caller {
+    pid: LocalPid,
+    env: OwnedEnv,
+    reference: SavedTerm,
+    cancellation: Arc<pool::Cancellation>,
 }
 
-fn parse_function_result_call<'a>(env: Env<'a>, result: FunctionResult) -> NifResult<Term<'a>> {
-    let parsed_value = result.parsed();
-    match parsed_value {
-        Some(Ok(response_baml_value)) => {
-            let baml_value = response_baml_value.0.clone().value();
-            let result_term = baml_value_to_term(env, &baml_value)?;
-            Ok((atoms::ok(), result_term).encode(env))
+impl Caller {
+    fn new(pid: LocalPid, reference: Term, cancellation: Arc<pool::Cancellation>) -> Caller {
+        let env = OwnedEnv::new();
+        let reference = env.save(reference);
+        Caller {
+            pid,
+            env,
+            reference,
+            cancellation,
         }
-        Some(Err(e)) => Ok((atoms::error(), format!("{:?}", e)).encode(env)),
-        None => Ok((atoms::error(), "No parsed value available").encode(env)),
+    }
+
+    // Sends the message `message` builds, unless it builds none
+    fn send(&self, message: impl for<'a> FnOnce(Env<'a>) -> Option<Term<'a>>) {
+        self.cancellation.send(|| {
+            OwnedEnv::new().run(|env| {
+                if let Some(message) = message(env) {
+                    let reference = self.env.run(|saved| self.reference.load(saved).in_env(env));
+                    let _ = env.send(&self.pid, (reference, message));
+                }
+            })
+        });
     }
 }
 
-fn parse_function_result_stream<'a>(
-    env: Env<'a>,
-    result: FunctionResult,
-) -> Result<Term<'a>, String> {
-    let parsed_value = result.parsed();
-    match parsed_value {
-        Some(Ok(response_baml_value)) => {
-            let baml_value = response_baml_value.0.clone().value();
-            let result_term = baml_value_to_term(env, &baml_value)
-                .map_err(|e| format!("Failed to convert BAML value to term: {:?}", e))?;
-            Ok(result_term)
-        }
-        Some(Err(e)) => Err(e.to_string()),
-        None => Err("No parsed value available".to_string()),
+fn encode_error<'a>(env: Env<'a>, error: Error) -> Term<'a> {
+    match error {
+        Error::Term(term) => (atoms::error(), term.encode(env)).encode(env),
+        error => (atoms::error(), format!("{:?}", error)).encode(env),
     }
 }
 
+// Runs `call` on the worker pool, see pool.rs, and returns the handle to
+// cancel it with. The caller gets `{reference, {:partial, value}}` messages
+// when streaming, and then `{reference, {tag, value}}` or
+// `{reference, {:error, error}}`. Calls cancelled before they start don't run,
+// and the orchestrator stops cancelled calls between requests.
+fn spawn_call(
+    pid: LocalPid,
+    reference: Term,
+    call: Call,
+    tag: rustler::Atom,
+    stream: bool,
+) -> ResourceArc<pool::CallResource> {
+    let (handle, cancellation) = pool::CallResource::new();
+    let caller = Caller::new(pid, reference, cancellation.clone());
+    pool::spawn(move || {
+        if cancellation.is_cancelled() {
+            return;
+        }
+        let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let request = prepare_request(call, cancellation)?;
+            let dynamic_classes = dynamic_classes(&request.runtime);
+            let on_partial = |value: BamlValue| {
+                caller.send(|env| {
+                    let result_term = baml_value_to_term(env, &value, &dynamic_classes).ok()?;
+                    Some((atoms::partial(), result_term).encode(env))
+                })
+            };
+            let value = run(
+                &request,
+                stream.then_some(&on_partial as &dyn Fn(BamlValue)),
+            )?;
+            Ok::<_, Error>((value, dynamic_classes))
+        }));
+
+        caller.send(|env| {
+            Some(match outcome {
+                Ok(Ok((value, dynamic_classes))) => {
+                    match baml_value_to_term(env, &value, &dynamic_classes) {
+                        Ok(result_term) => (tag, result_term).encode(env),
+                        Err(e) => encode_error(env, e),
+                    }
+                }
+                Ok(Err(e)) => encode_error(env, e),
+                Err(_) => (atoms::error(), "The call panicked").encode(env),
+            })
+        });
+    });
+    handle
+}
+
 #[rustler::nif(schedule = "DirtyIo")]
 fn call<'a>(
-    env: Env<'a>,
+    pid: LocalPid,
+    reference: Term<'a>,
     function_name: String,
     arguments: Term<'a>,
-    path: String,
+    runtime: Term<'a>,
     collectors: Vec<ResourceArc<collector::CollectorResource>>,
     client_registry: Term<'a>,
     tb: Term<'a>,
-) -> NifResult<Term<'a>> {
-    let (runtime, params, ctx, collectors, client_registry, tb) =
-        prepare_request(arguments, path, collectors, client_registry, tb)?;
-
-    // Call function synchronously
-    let (result, _trace_id) = runtime.call_function_sync(
+) -> NifResult<ResourceArc<pool::CallResource>> {
+    let call = decode_call(
         function_name,
-        &params,
-        &ctx,
-        tb.as_ref(),              // type builder (optional)
-        client_registry.as_ref(), // client registry (optional)
+        arguments,
+        runtime,
         collectors,
-        std::env::vars().collect(),
-    );
-
-    // Handle result
-    match result {
-        Ok(function_result) => parse_function_result_call(env, function_result),
-        Err(e) => Ok((atoms::error(), format!("{:?}", e)).encode(env)),
-    }
+        client_registry,
+        tb,
+    )?;
+    Ok(spawn_call(pid, reference, call, atoms::ok(), false))
 }
 
 #[rustler::nif(schedule = "DirtyIo")]
 fn stream<'a>(
-    env: Env<'a>,
-    pid: Term<'a>,
+    pid: LocalPid,
     reference: Term<'a>,
     function_name: String,
     arguments: Term<'a>,
-    path: String,
+    runtime: Term<'a>,
     collectors: Vec<ResourceArc<collector::CollectorResource>>,
     client_registry: Term<'a>,
     tb: Term<'a>,
-) -> NifResult<Term<'a>> {
-    let pid = pid.decode::<LocalPid>()?;
-    let (runtime, params, ctx, collectors, client_registry, tb) =
-        prepare_request(arguments, path, collectors, client_registry, tb)?;
-
-    let on_event = |r: FunctionResult| {
-        match parse_function_result_stream(env, r) {
-            Ok(result_term) => {
-                let wrapped_result = (reference, (atoms::partial(), result_term)).encode(env);
-                let _ = env.send(&pid, wrapped_result);
-            }
-            Err(_) => {
-                // Do nothing on error because this can happen when
-                // the result cannot be coerced to a BAML value.
-                // This can happen when the result is incomplete.
-                // We'll get the final result and check for a real error then.
-                return;
-            }
-        }
-    };
-
-    let result = runtime.stream_function(
+) -> NifResult<ResourceArc<pool::CallResource>> {
+    let call = decode_call(
         function_name,
-        &params,
-        &ctx,
-        tb.as_ref(),
-        client_registry.as_ref(),
+        arguments,
+        runtime,
         collectors,
-        std::env::vars().collect(),
-    );
+        client_registry,
+        tb,
+    )?;
+    Ok(spawn_call(pid, reference, call, atoms::done(), true))
+}
 
-    match result {
-        Ok(mut stream) => {
-            let (result, _trace_id) =
-                stream.run_sync(Some(on_event), &ctx, None, None, std::env::vars().collect());
-            match result {
-                Ok(r) => match r.parsed() {
-                    Some(Ok(result)) => {
-                        let baml_value = result.0.clone().value();
-                        let result_term = baml_value_to_term(env, &baml_value)?;
-                        Ok((atoms::done(), result_term).encode(env))
-                    }
-                    Some(Err(e)) => Ok((atoms::error(), format!("{:?}", e)).encode(env)),
-                    None => Ok((atoms::error(), "No parsed value available").encode(env)),
-                },
-                Err(e) => Ok((atoms::error(), format!("{:?}", e)).encode(env)),
-            }
-        }
-        Err(e) => Ok((atoms::error(), format!("{:?}", e)).encode(env)),
-    }
+// Cancels a call or a stream. No message of the call arrives once this returns.
+#[rustler::nif]
+fn cancel(call: ResourceArc<pool::CallResource>) -> rustler::Atom {
+    call.cancellation.cancel();
+    atoms::ok()
 }
 
 #[rustler::nif]
@@ -420,6 +628,96 @@ fn collector_last_function_log(
     collector.last_function_log()
 }
 
+#[rustler::nif]
+fn client_registry_new(
+    client_registry: Term,
+) -> NifResult<ResourceArc<client_registry::ClientRegistryResource>> {
+    client_registry::ClientRegistryResource::new(client_registry)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn type_builder_new(
+    runtime: Term,
+    tb: Term,
+) -> NifResult<ResourceArc<type_builder::TypeBuilderResource>> {
+    type_builder::TypeBuilderResource::new(runtime, tb)
+}
+
+#[rustler::nif]
+fn type_builder_add_class(
+    tb: ResourceArc<type_builder::TypeBuilderResource>,
+    name: String,
+    attributes: Term,
+) -> NifResult<rustler::Atom> {
+    tb.add_class(name, attributes)?;
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+fn type_builder_add_property(
+    tb: ResourceArc<type_builder::TypeBuilderResource>,
+    class: String,
+    property: Term,
+) -> NifResult<rustler::Atom> {
+    tb.add_property(class, property)?;
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+fn type_builder_add_enum_value(
+    tb: ResourceArc<type_builder::TypeBuilderResource>,
+    enum_name: String,
+    value: Term,
+) -> NifResult<rustler::Atom> {
+    tb.add_enum_value(enum_name, value)?;
+    Ok(atoms::ok())
+}
+
+#[rustler::nif]
+fn type_builder_from_json_schema<'a>(
+    env: Env<'a>,
+    schema: Term<'a>,
+    name: Option<String>,
+) -> NifResult<Term<'a>> {
+    // The schema is either JSON or an already decoded map
+    let schema = match schema.decode::<String>() {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|e| Error::Term(Box::new(format!("Invalid JSON Schema: {}", e))))?,
+        Err(_) => serde_json::to_value(term_to_baml_value(schema)?)
+            .map_err(|e| Error::Term(Box::new(format!("Invalid JSON Schema: {}", e))))?,
+    };
+    let definitions = json_schema::to_definitions(&schema, name)?;
+    let entries = definitions
+        .iter()
+        .map(|definition| definition.encode(env))
+        .collect::<NifResult<Vec<_>>>()?;
+    Ok(entries.encode(env))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn output_format(function_name: String, runtime: Term, tb: Term) -> NifResult<String> {
+    output_format::render(&function_name, runtime, tb)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn runtime_new(path: String, opts: Term) -> NifResult<ResourceArc<runtime::RuntimeResource>> {
+    runtime::RuntimeResource::new(path, opts)
+}
+
+#[rustler::nif]
+fn runtime_circuit_breakers(
+    env: Env,
+    runtime: ResourceArc<runtime::RuntimeResource>,
+) -> NifResult<Term> {
+    runtime.circuit_breaker.encode_state(env)
+}
+
+#[rustler::nif]
+fn reply(reply: ResourceArc<reply::ReplyResource>, term: Term) -> rustler::Atom {
+    reply.put(term);
+    atoms::ok()
+}
+
 #[rustler::nif]
 fn parse_baml(env: Env, path: Option<String>) -> NifResult<Term> {
     let path = path.unwrap_or_else(|| "baml_src".to_string());
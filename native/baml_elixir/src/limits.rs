@@ -0,0 +1,231 @@
+use rustler::{Error, MapIterator, NifMap, Term};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{atoms, term_to_baml_value, term_to_string};
+
+#[derive(Default)]
+pub struct ClientLimits {
+    max_concurrency: Option<usize>,
+    requests_per_minute: Option<f64>,
+    tokens_per_minute: Option<f64>,
+    queue_timeout: Option<Duration>,
+}
+
+// Parses limits given per client name, like:
+// %{"GPT4" => %{max_concurrency: 4, requests_per_minute: 60, tokens_per_minute: 90_000, queue_timeout_ms: 5_000}}
+pub fn from_term(term: Term) -> Result<HashMap<String, ClientLimits>, Error> {
+    let iter = MapIterator::new(term).ok_or(Error::Term(Box::new("Limits must be a map")))?;
+
+    let mut limits = HashMap::new();
+    for (name_term, limits_term) in iter {
+        let name = term_to_string(name_term)?;
+        let iter = MapIterator::new(limits_term).ok_or(Error::Term(Box::new(format!(
+            "Limits of client {} must be a map",
+            name
+        ))))?;
+
+        let mut client_limits = ClientLimits::default();
+        for (key_term, value_term) in iter {
+            let key = term_to_string(key_term)?;
+            let value = match term_to_baml_value(value_term)? {
+                baml_types::BamlValue::Int(n) if n > 0 => n as u64,
+                _ => {
+                    return Err(Error::Term(Box::new(format!(
+                        "Limit {} of client {} must be a positive integer",
+                        key, name
+                    ))))
+                }
+            };
+            match key.as_str() {
+                "max_concurrency" => client_limits.max_concurrency = Some(value as usize),
+                "requests_per_minute" => client_limits.requests_per_minute = Some(value as f64),
+                "tokens_per_minute" => client_limits.tokens_per_minute = Some(value as f64),
+                "queue_timeout_ms" => {
+                    client_limits.queue_timeout = Some(Duration::from_millis(value))
+                }
+                _ => {
+                    return Err(Error::Term(Box::new(format!(
+                        "Unknown limit {} for client {}",
+                        key, name
+                    ))))
+                }
+            }
+        }
+        limits.insert(name, client_limits);
+    }
+
+    Ok(limits)
+}
+
+#[derive(NifMap)]
+struct QueueTimeout {
+    kind: rustler::Atom,
+    client: String,
+    message: String,
+}
+
+// Refills continuously at `capacity` per minute, up to `capacity`. Tokens
+// charged after a request can take it below zero, in which case later requests
+// wait until the debt is paid back.
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64) -> Self {
+        Bucket {
+            capacity,
+            available: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    fn time_until(&self, amount: f64) -> Duration {
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) * 60.0 / self.capacity)
+        }
+    }
+}
+
+struct ClientState {
+    in_flight: usize,
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+// Enforces ClientLimits across all calls sharing a runtime resource.
+//
+// Every request waits for the limits of the client it's sent to, including
+// the retries and the clients of fallback and round-robin strategies, and
+// the tokens it used are charged once it's done. Requests wait on the thread
+// running their call, never on a scheduler of the VM.
+pub struct Limiter {
+    limits: HashMap<String, ClientLimits>,
+    state: Mutex<HashMap<String, ClientState>>,
+    released: Condvar,
+}
+
+impl Limiter {
+    pub fn new(limits: HashMap<String, ClientLimits>) -> Self {
+        let state = limits
+            .iter()
+            .map(|(name, limits)| {
+                let state = ClientState {
+                    in_flight: 0,
+                    requests: limits.requests_per_minute.map(Bucket::new),
+                    tokens: limits.tokens_per_minute.map(Bucket::new),
+                };
+                (name.clone(), state)
+            })
+            .collect();
+
+        Limiter {
+            limits,
+            state: Mutex::new(state),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    // Waits until a request may be sent to `client`, or until its queue
+    // timeout expires. Clients without limits don't wait.
+    pub fn acquire(self: &Arc<Self>, client: &str) -> Result<Option<Permit>, Error> {
+        let Some(limits) = self.limits.get(client) else {
+            return Ok(None);
+        };
+        let deadline = limits.queue_timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let client_state = state.get_mut(client).unwrap();
+
+            let mut wait = Duration::ZERO;
+            if let Some(requests) = &mut client_state.requests {
+                requests.refill(now);
+                wait = wait.max(requests.time_until(1.0));
+            }
+            if let Some(tokens) = &mut client_state.tokens {
+                tokens.refill(now);
+                wait = wait.max(tokens.time_until(0.0));
+            }
+            let has_capacity = limits
+                .max_concurrency
+                .is_none_or(|max| client_state.in_flight < max);
+
+            if has_capacity && wait.is_zero() {
+                client_state.in_flight += 1;
+                if let Some(requests) = &mut client_state.requests {
+                    requests.available -= 1.0;
+                }
+                return Ok(Some(Permit {
+                    limiter: self.clone(),
+                    client: client.to_string(),
+                    tokens: 0.0,
+                }));
+            }
+
+            // Without capacity, wait to be notified when a request finishes
+            if wait.is_zero() {
+                wait = Duration::from_secs(60);
+            }
+            if let Some(deadline) = deadline {
+                if now >= deadline {
+                    return Err(Error::Term(Box::new(QueueTimeout {
+                        kind: atoms::queue_timeout(),
+                        client: client.to_string(),
+                        message: format!("Timed out waiting for the limits of client {}", client),
+                    })));
+                }
+                wait = wait.min(deadline - now);
+            }
+            state = self.released.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    fn release(&self, client: &str, tokens: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(client_state) = state.get_mut(client) {
+            client_state.in_flight -= 1;
+            if let Some(bucket) = &mut client_state.tokens {
+                bucket.available -= tokens;
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+// Held while a request is sent. The tokens the request used are charged when
+// it's dropped.
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    client: String,
+    tokens: f64,
+}
+
+impl Permit {
+    pub fn charge(&mut self, tokens: f64) {
+        self.tokens = tokens;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client, self.tokens);
+    }
+}
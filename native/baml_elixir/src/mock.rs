@@ -0,0 +1,154 @@
+use baml_types::BamlValue;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::transport::HttpRequest;
+
+#[derive(Clone)]
+enum Response {
+    // The text of the response, streamed in chunks
+    Chunks(Vec<String>),
+    Error(String),
+}
+
+enum Responses {
+    Always(Response),
+    // Served in order, one per request
+    Queue(VecDeque<Response>),
+}
+
+// The canned responses of a "mock" client, served instead of sending its
+// requests to a provider. A client's responses are shared by the calls using
+// the same client registry, so queues carry over between calls made with a
+// client registry resource.
+pub struct Mock {
+    name: String,
+    latency: Duration,
+    // For any function, served once the responses of the function are gone
+    response: Option<Response>,
+    queue: Mutex<VecDeque<Response>>,
+    functions: Mutex<HashMap<String, Responses>>,
+}
+
+impl Mock {
+    pub fn new(name: &str, latency: Duration) -> Mock {
+        Mock {
+            name: name.to_string(),
+            latency,
+            response: None,
+            queue: Mutex::new(VecDeque::new()),
+            functions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Sets the `response` of the client, served for every request
+    pub fn set_response(&mut self, value: BamlValue) -> Result<(), String> {
+        self.response = Some(response(value)?);
+        Ok(())
+    }
+
+    // Sets the `responses` of the client, either a list served in order or a
+    // map of function names to a response or a list of them
+    pub fn set_responses(&mut self, value: BamlValue) -> Result<(), String> {
+        match value {
+            BamlValue::List(values) => {
+                *self.queue.get_mut().unwrap() = queue(values)?;
+            }
+            BamlValue::Map(functions) => {
+                let mut responses = HashMap::new();
+                for (function, value) in functions {
+                    let function_responses = match value {
+                        BamlValue::List(values) => Responses::Queue(queue(values)?),
+                        value => Responses::Always(response(value)?),
+                    };
+                    responses.insert(function, function_responses);
+                }
+                *self.functions.get_mut().unwrap() = responses;
+            }
+            _ => return Err("responses must be a list or a map of function names".to_string()),
+        }
+        Ok(())
+    }
+
+    // Returns the text of the next response to `request` and the tokens it
+    // used, which mocks don't count. With `on_text`, the response is streamed
+    // and `on_text` is called with the text received so far after each chunk.
+    pub fn respond(
+        &self,
+        request: &HttpRequest,
+        mut on_text: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<(String, f64), String> {
+        let chunks = match self.next(&request.function)? {
+            Response::Chunks(chunks) => chunks,
+            Response::Error(message) => {
+                std::thread::sleep(self.latency);
+                return Err(message);
+            }
+        };
+
+        let mut text = String::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            if index == 0 || on_text.is_some() {
+                std::thread::sleep(self.latency);
+            }
+            text.push_str(chunk);
+            if let Some(on_text) = on_text.as_deref_mut() {
+                on_text(&text);
+            }
+        }
+        Ok((text, 0.0))
+    }
+
+    fn next(&self, function: &str) -> Result<Response, String> {
+        match self.functions.lock().unwrap().get_mut(function) {
+            Some(Responses::Always(response)) => return Ok(response.clone()),
+            Some(Responses::Queue(queue)) => {
+                if let Some(response) = queue.pop_front() {
+                    return Ok(response);
+                }
+            }
+            None => {}
+        }
+        if let Some(response) = self.queue.lock().unwrap().pop_front() {
+            return Ok(response);
+        }
+        self.response.clone().ok_or_else(|| {
+            format!(
+                "Mock client {} has no response left for {}",
+                self.name, function
+            )
+        })
+    }
+}
+
+// A response is the text to respond with, a map of the `chunks` to stream it
+// in, or a map with the `error` to fail the request with.
+fn response(value: BamlValue) -> Result<Response, String> {
+    match value {
+        BamlValue::String(text) => Ok(Response::Chunks(vec![text])),
+        BamlValue::Map(mut map) if map.len() == 1 => {
+            match (map.shift_remove("chunks"), map.shift_remove("error")) {
+                (Some(BamlValue::List(chunks)), None) => chunks
+                    .into_iter()
+                    .map(|chunk| match chunk {
+                        BamlValue::String(chunk) => Ok(chunk),
+                        _ => Err("Response chunks must be strings".to_string()),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Response::Chunks),
+                (None, Some(BamlValue::String(message))) => Ok(Response::Error(message)),
+                _ => Err(invalid_response()),
+            }
+        }
+        _ => Err(invalid_response()),
+    }
+}
+
+fn queue(values: Vec<BamlValue>) -> Result<VecDeque<Response>, String> {
+    values.into_iter().map(response).collect()
+}
+
+fn invalid_response() -> String {
+    "A response must be a string, %{chunks: [string]} or %{error: string}".to_string()
+}
@@ -0,0 +1,507 @@
+use baml_runtime::client_registry::ClientRegistry;
+use baml_runtime::tracingv2::storage::storage::{Collector, LLMCallKind};
+use baml_runtime::BamlRuntime;
+use baml_types::BamlValue;
+use rustler::{Error, ResourceArc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::cassette::Cassette;
+use crate::circuit_breaker;
+use crate::client_registry::Spec;
+use crate::clients::{self, Strategy};
+use crate::mock::Mock;
+use crate::runtime::RuntimeResource;
+use crate::transport::{self, Api, Handler, RawResponse};
+use crate::{run_baml, Request};
+
+// The next client of each round-robin client, shared by the calls made with a
+// runtime resource or else by all calls
+#[derive(Default)]
+pub struct Rotations {
+    next: Mutex<HashMap<String, usize>>,
+}
+
+impl Rotations {
+    pub fn shared() -> Arc<Rotations> {
+        static SHARED: OnceLock<Arc<Rotations>> = OnceLock::new();
+        SHARED.get_or_init(Arc::default).clone()
+    }
+
+    // The index of the client `round_robin` uses next, starting at `start`
+    fn next(&self, round_robin: &str, start: usize, len: usize) -> usize {
+        let mut next = self.next.lock().unwrap();
+        let index = next.entry(round_robin.to_string()).or_insert(start);
+        let current = *index % len;
+        *index = current + 1;
+        current
+    }
+}
+
+// How the orchestrator runs a call whose requests aren't all sent by BAML,
+// like the requests of "elixir" and "mock" clients, whose requests must each
+// pass the client limits and circuit breaker of its runtime resource, or whose
+// requests are recorded to or replayed from the cassette of its runtime
+// resource.
+//
+// It follows the strategies of fallback and round-robin clients and the retry
+// policies of the clients like BAML does, one request at a time. Requests to
+// the other clients are still made by BAML, as calls of their own.
+pub struct Orchestration {
+    spec: Spec,
+    // Set when the requests must pass the limits and circuit breaker of the
+    // runtime resource
+    gates: Option<ResourceArc<RuntimeResource>>,
+    // The API of the responses of each "elixir" client
+    apis: HashMap<String, Api>,
+    handlers: HashMap<String, Handler>,
+    mocks: HashMap<String, Arc<Mock>>,
+    cassette: Option<Arc<Cassette>>,
+    rotations: Arc<Rotations>,
+}
+
+impl Orchestration {
+    // The orchestration of a call to `function_name`, if it needs one. Elixir
+    // clients serving APIs whose responses can't be read are refused, and so
+    // are collectors when requests aren't sent by BAML, since they only log
+    // the requests BAML sends.
+    pub fn new(
+        spec: Option<&Spec>,
+        function_name: &str,
+        runtime: &BamlRuntime,
+        resource: Option<&ResourceArc<RuntimeResource>>,
+        collectors: bool,
+    ) -> Result<Option<Orchestration>, Error> {
+        let spec = spec.cloned().unwrap_or_default();
+        let handlers = spec.handlers();
+        let mocks = spec.mocks();
+        let gates = resource
+            .filter(|r| !r.limiter.is_empty() || r.circuit_breaker.is_enabled())
+            .cloned();
+
+        let cassette = resource.and_then(|r| r.cassette.clone());
+        let replaying = cassette.as_ref().is_some_and(|c| c.is_replaying());
+
+        let clients = spec.reachable_clients(function_name, runtime);
+        let served = |client: &String| handlers.contains_key(client) || mocks.contains_key(client);
+        let unsent = replaying || clients.iter().any(served);
+        if !unsent && gates.is_none() && cassette.is_none() {
+            return Ok(None);
+        }
+        if collectors && unsent {
+            return Err(Error::Term(Box::new(
+                "Collectors only log the requests BAML sends, so they can't be used with mock \
+                 and elixir clients or replayed cassettes",
+            )));
+        }
+
+        let mut apis = HashMap::new();
+        for client in clients {
+            if !handlers.contains_key(&client) {
+                continue;
+            }
+            let provider = spec.provider_of(&client, runtime).unwrap_or_default();
+            let api = Api::of(&provider).ok_or_else(|| {
+                Error::Term(Box::new(format!(
+                    "Client {} serves the API of {}, whose responses can't be read",
+                    client, provider
+                )))
+            })?;
+            apis.insert(client, api);
+        }
+
+        Ok(Some(Orchestration {
+            spec,
+            gates,
+            apis,
+            handlers,
+            mocks,
+            cassette,
+            rotations: resource.map_or_else(Rotations::shared, |r| r.rotations.clone()),
+        }))
+    }
+
+    // The cassette requests are recorded to, if recording
+    fn recording(&self) -> Option<&Cassette> {
+        self.cassette.as_deref().filter(|c| !c.is_replaying())
+    }
+}
+
+enum Outcome {
+    Done(BamlValue),
+    Failed,
+    // Skipped by the limits or circuit breaker of the client, which isn't
+    // retried
+    Gated,
+}
+
+// A request sent to a client, with the tokens it used and whether the client
+// failed to answer it
+struct Sent {
+    tokens: f64,
+    failed: bool,
+}
+
+// The state of a call run by the orchestrator
+struct Run<'a> {
+    orchestration: &'a Orchestration,
+    request: &'a Request,
+    on_partial: Option<&'a dyn Fn(BamlValue)>,
+    // Why each request failed or was skipped, in order
+    errors: Vec<String>,
+    // Why the first skipped request was skipped, returned when no request was
+    // sent
+    gate: Option<Error>,
+    sent: usize,
+}
+
+// Runs the call of `request`, starting with the primary client of the
+// registry or else the client the function declares. With `on_partial`, the
+// call is streamed and `on_partial` is called with each partial result that
+// can be parsed.
+pub fn run(
+    orchestration: &Orchestration,
+    request: &Request,
+    on_partial: Option<&dyn Fn(BamlValue)>,
+) -> Result<BamlValue, Error> {
+    let start = match orchestration.spec.primary() {
+        Some(primary) => primary.to_string(),
+        None => {
+            clients::function_client(&request.runtime, &request.function_name).ok_or_else(|| {
+                Error::Term(Box::new(format!(
+                    "Function {} has no client",
+                    request.function_name
+                )))
+            })?
+        }
+    };
+
+    // Calls all of whose clients have open circuits fail fast, as the client
+    // they start with, whether it's a fallback or round-robin client or not
+    if let Some(resource) = &orchestration.gates {
+        let clients = orchestration
+            .spec
+            .reachable_clients(&request.function_name, &request.runtime);
+        if let Some(retry_after) = resource.circuit_breaker.retry_after(&clients) {
+            return Err(circuit_breaker::circuit_open(&start, retry_after));
+        }
+    }
+
+    let mut run = Run {
+        orchestration,
+        request,
+        on_partial,
+        errors: Vec::new(),
+        gate: None,
+        sent: 0,
+    };
+    match run.client(&start, &mut Vec::new()) {
+        Outcome::Done(value) => Ok(value),
+        Outcome::Failed | Outcome::Gated => match run.gate.take() {
+            Some(gate) if run.sent == 0 => Err(gate),
+            _ => Err(Error::Term(Box::new(format!(
+                "All clients failed: {}",
+                run.errors.join("; ")
+            )))),
+        },
+    }
+}
+
+impl Run<'_> {
+    // Runs `client` and retries it as its retry policy says. Strategy clients
+    // run the clients of their strategy, `path` being the strategy clients
+    // that led to `client`.
+    fn client(&mut self, client: &str, path: &mut Vec<String>) -> Outcome {
+        if path.iter().any(|c| c == client) {
+            self.errors
+                .push(format!("{}: Its strategy leads back to it", client));
+            return Outcome::Failed;
+        }
+
+        let runtime = self.request.runtime.clone();
+        let spec = &self.orchestration.spec;
+        let retry_policy = spec
+            .retry_policy_of(client, &runtime)
+            .and_then(|name| clients::retry_policy(&runtime, &name));
+        let strategy = spec.strategy_of(client, &runtime);
+
+        path.push(client.to_string());
+        let retries = retry_policy.as_ref().map_or(0, |policy| policy.max_retries);
+        let mut outcome = Outcome::Failed;
+        for retry in 0..=retries {
+            if let (true, Some(policy)) = (retry > 0, &retry_policy) {
+                std::thread::sleep(policy.delay(retry));
+            }
+            outcome = match &strategy {
+                Some(Strategy::Fallback(clients)) => self.fallback(clients, path),
+                Some(Strategy::RoundRobin { clients, start }) if !clients.is_empty() => {
+                    let index = self
+                        .orchestration
+                        .rotations
+                        .next(client, *start, clients.len());
+                    self.client(&clients[index], path)
+                }
+                Some(Strategy::RoundRobin { .. }) => Outcome::Failed,
+                None => self.attempt(client),
+            };
+            if !matches!(outcome, Outcome::Failed) {
+                break;
+            }
+        }
+        path.pop();
+        outcome
+    }
+
+    fn fallback(&mut self, clients: &[String], path: &mut Vec<String>) -> Outcome {
+        let mut outcome = Outcome::Failed;
+        for client in clients {
+            outcome = self.client(client, path);
+            if let Outcome::Done(_) = outcome {
+                break;
+            }
+        }
+        outcome
+    }
+
+    // Sends a single request to `client`, once it passes the circuit breaker
+    // and the limits of the client, unless the call was cancelled
+    fn attempt(&mut self, client: &str) -> Outcome {
+        if self.request.cancellation.is_cancelled() {
+            self.errors
+                .push(format!("{}: The call was cancelled", client));
+            return Outcome::Failed;
+        }
+
+        let orchestration = self.orchestration;
+        let mut permit = None;
+        if let Some(resource) = &orchestration.gates {
+            if let Err(retry_after) = resource.circuit_breaker.pass(client) {
+                let gate = circuit_breaker::circuit_open(client, retry_after);
+                return self.gated(client, gate, "Its circuit is open");
+            }
+            match resource.limiter.acquire(client) {
+                Ok(acquired) => permit = acquired,
+                Err(gate) => {
+                    resource.circuit_breaker.release(client);
+                    return self.gated(client, gate, "Timed out waiting for its limits");
+                }
+            }
+        }
+
+        let mut registry = self
+            .request
+            .client_registry
+            .clone()
+            .unwrap_or_else(ClientRegistry::new);
+        registry.set_primary(client.to_string());
+
+        let mut sent = None;
+        let replaying = orchestration
+            .cassette
+            .as_deref()
+            .filter(|c| c.is_replaying());
+        let result = match replaying {
+            Some(cassette) => self.replay(cassette, client, &registry, &mut sent),
+            None if orchestration.mocks.contains_key(client)
+                || orchestration.handlers.contains_key(client) =>
+            {
+                self.exchange(client, &registry, &mut sent)
+            }
+            None => self.baml(client, &registry, &mut sent),
+        };
+
+        if let Some(resource) = &orchestration.gates {
+            match &sent {
+                Some(sent) => {
+                    if let Some(permit) = &mut permit {
+                        permit.charge(sent.tokens);
+                    }
+                    resource.circuit_breaker.record(client, sent.failed);
+                }
+                None => resource.circuit_breaker.release(client),
+            }
+        }
+        if sent.is_some() {
+            self.sent += 1;
+        }
+
+        match result {
+            Ok(value) => Outcome::Done(value),
+            Err(e) => {
+                self.errors.push(format!("{}: {}", client, e));
+                Outcome::Failed
+            }
+        }
+    }
+
+    fn gated(&mut self, client: &str, gate: Error, reason: &str) -> Outcome {
+        self.errors.push(format!("{}: {}", client, reason));
+        self.gate.get_or_insert(gate);
+        Outcome::Gated
+    }
+
+    // Has BAML send the request to `client`, the primary client of
+    // `registry`. The request is logged to a collector of its own, when it has
+    // to pass gates or be recorded, to read how it went.
+    fn baml(
+        &self,
+        client: &str,
+        registry: &ClientRegistry,
+        sent: &mut Option<Sent>,
+    ) -> Result<BamlValue, String> {
+        let recording = self.orchestration.recording();
+        if self.orchestration.gates.is_none() && recording.is_none() {
+            return run_baml(self.request, Some(registry), None, self.on_partial);
+        }
+
+        let collector = Arc::new(Collector::new(Some("baml_elixir_runtime".to_string())));
+        let result = run_baml(
+            self.request,
+            Some(registry),
+            Some(collector.clone()),
+            self.on_partial,
+        );
+        *sent = logged_request(&collector);
+
+        if let Some(cassette) = recording {
+            let http_request = transport::build_request(
+                self.request,
+                registry,
+                client,
+                self.on_partial.is_some(),
+            )?;
+            let (response, text) = logged_response(&collector);
+            let outcome = match (text, &result) {
+                (Some(text), _) => Ok(text),
+                (None, Err(e)) => Err(e.clone()),
+                (None, Ok(_)) => Err("BAML logged no response".to_string()),
+            };
+            cassette.record(&http_request, response.as_ref(), &outcome)?;
+        }
+        result
+    }
+
+    // Hands the request BAML builds for `client` to its Elixir process or its
+    // mock, and has BAML parse the response
+    fn exchange(
+        &self,
+        client: &str,
+        registry: &ClientRegistry,
+        sent: &mut Option<Sent>,
+    ) -> Result<BamlValue, String> {
+        let orchestration = self.orchestration;
+        let request = self.request;
+        let on_partial = self.on_partial;
+
+        let http_request =
+            transport::build_request(request, registry, client, on_partial.is_some())?;
+
+        let mut on_text = |text: &str| {
+            if let Some(on_partial) = on_partial {
+                if let Ok(value) = transport::parse(request, registry, text, true) {
+                    on_partial(value);
+                }
+            }
+        };
+        let on_text: Option<&mut dyn FnMut(&str)> = match on_partial {
+            Some(_) => Some(&mut on_text),
+            None => None,
+        };
+
+        let (response, raw_response) = match orchestration.mocks.get(client) {
+            Some(mock) => (mock.respond(&http_request, on_text), None),
+            None => {
+                let raw_response = orchestration.handlers[client].send(
+                    &http_request,
+                    orchestration.apis[client],
+                    on_text,
+                );
+                let response = match &raw_response {
+                    Ok(raw_response) => {
+                        transport::read_response(orchestration.apis[client], raw_response)
+                    }
+                    Err(e) => Err(e.clone()),
+                };
+                (response, raw_response.ok())
+            }
+        };
+
+        *sent = Some(Sent {
+            tokens: response.as_ref().map_or(0.0, |(_, tokens)| *tokens),
+            failed: response.is_err(),
+        });
+        if let Some(cassette) = orchestration.recording() {
+            let outcome = match &response {
+                Ok((text, _)) => Ok(text.clone()),
+                Err(e) => Err(e.clone()),
+            };
+            cassette.record(&http_request, raw_response.as_ref(), &outcome)?;
+        }
+        let (text, _) = response?;
+        transport::parse(request, registry, &text, false)
+    }
+
+    // Has BAML parse the response the cassette recorded for the request BAML
+    // builds for `client`. Streams get the whole response as a single partial
+    // result.
+    fn replay(
+        &self,
+        cassette: &Cassette,
+        client: &str,
+        registry: &ClientRegistry,
+        sent: &mut Option<Sent>,
+    ) -> Result<BamlValue, String> {
+        let http_request =
+            transport::build_request(self.request, registry, client, self.on_partial.is_some())?;
+        let text = cassette.replay(&http_request);
+        *sent = Some(Sent {
+            tokens: 0.0,
+            failed: text.is_err(),
+        });
+        let text = text?;
+
+        if let Some(on_partial) = self.on_partial {
+            if let Ok(value) = transport::parse(self.request, registry, &text, true) {
+                on_partial(value);
+            }
+        }
+        transport::parse(self.request, registry, &text, false)
+    }
+}
+
+// The request BAML logged to `collector`. Requests without a successful
+// response, like timeouts or rate limit errors, count as failures of the
+// client.
+fn logged_request(collector: &Collector) -> Option<Sent> {
+    let mut log = collector.last_function_log()?;
+    let calls = log.calls();
+    let (usage, response) = match calls.last()? {
+        LLMCallKind::Basic(call) => (call.usage.clone(), call.response.clone()),
+        LLMCallKind::Stream(call) => (call.usage.clone(), call.response.clone()),
+    };
+    let tokens = usage
+        .map(|u| u.input_tokens.unwrap_or(0) + u.output_tokens.unwrap_or(0))
+        .unwrap_or(0);
+    Some(Sent {
+        tokens: tokens as f64,
+        failed: response.is_none_or(|r| !(200..300).contains(&r.status)),
+    })
+}
+
+// The response BAML logged to `collector`, as it was received when it was
+// complete, and its text
+fn logged_response(collector: &Collector) -> (Option<RawResponse>, Option<String>) {
+    let Some(mut log) = collector.last_function_log() else {
+        return (None, None);
+    };
+    let text = log.raw_llm_response();
+    let response = match log.calls().last() {
+        Some(LLMCallKind::Basic(call)) => call.response.as_deref().map(|response| RawResponse {
+            status: response.status,
+            body: response.body.text().unwrap_or_default().to_string(),
+            stream: false,
+        }),
+        _ => None,
+    };
+    (response, text)
+}
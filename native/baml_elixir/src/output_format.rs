@@ -0,0 +1,108 @@
+use baml_types::{BamlMap, BamlValue};
+use rustler::{Error, ResourceArc, Term};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{field_type, runtime, type_builder};
+
+// The names of the function and client generated to render output formats,
+// which are unlikely to clash with the ones of the BAML source files
+const FUNCTION: &str = "BamlElixirOutputFormat";
+const CLIENT: &str = "BamlElixirOutputFormatClient";
+
+// Renders `ctx.output_format` for the output type of a function, with the
+// classes and enums of a type builder, as the model would see it in the
+// prompt.
+//
+// BAML only renders output formats as part of a prompt, so this loads the
+// BAML source files, or the ones of a runtime resource along with the source
+// it generated, with a function returning the same type whose prompt is
+// nothing but `{{ ctx.output_format }}`, and reads the prompt back from the
+// request that function would send.
+pub fn render(function_name: &str, runtime: Term, tb: Term) -> Result<String, Error> {
+    let env_vars: HashMap<String, String> = std::env::vars().collect();
+    let (path, runtime, mut files) = match runtime.decode::<ResourceArc<runtime::RuntimeResource>>()
+    {
+        Ok(resource) => (
+            resource.path.clone(),
+            resource.runtime.clone(),
+            (*resource.files).clone(),
+        ),
+        Err(_) => {
+            let path = runtime.decode::<String>()?;
+            let (runtime, files) = runtime::load(&path, env_vars.clone(), None)?;
+            (path, Arc::new(runtime), files)
+        }
+    };
+
+    let function = runtime
+        .inner
+        .ir
+        .walk_functions()
+        .find(|function| function.name() == function_name)
+        .ok_or_else(|| Error::Term(Box::new(format!("Unknown function {}", function_name))))?;
+    let generated = format!(
+        r##"
+client<llm> {client} {{
+  provider openai-generic
+  options {{
+    base_url "http://localhost"
+    model "output-format"
+  }}
+}}
+
+function {function}() -> {output} {{
+  client {client}
+  prompt #"{{{{ ctx.output_format }}}}"#
+}}
+"##,
+        client = CLIENT,
+        function = FUNCTION,
+        output = field_type::to_baml(function.output()),
+    );
+
+    runtime::add_generated(&path, &mut files, &generated);
+    let runtime = runtime::from_files(&path, &files, env_vars.clone())?;
+    let tb = type_builder::from_term(tb, &runtime)?;
+    let ctx = runtime.create_ctx_manager(BamlValue::String("elixir".to_string()), None);
+    let request = runtime
+        .build_request_sync(
+            FUNCTION.to_string(),
+            &BamlMap::new(),
+            &ctx,
+            tb.as_ref(),
+            None,
+            false,
+            env_vars,
+        )
+        .map_err(|e| Error::Term(Box::new(format!("{:?}", e))))?;
+
+    let body: Value = serde_json::from_str(request.body.text().unwrap_or_default())
+        .map_err(|e| Error::Term(Box::new(e.to_string())))?;
+    prompt_text(&body).ok_or_else(|| {
+        Error::Term(Box::new(format!(
+            "Failed to render the output format of {}",
+            function_name
+        )))
+    })
+}
+
+// The text of the messages of an OpenAI request, whose content is either a
+// string or a list of parts
+fn prompt_text(body: &Value) -> Option<String> {
+    let messages = body.get("messages")?.as_array()?;
+    let mut text = String::new();
+    for message in messages {
+        match message.get("content")? {
+            Value::String(content) => text.push_str(content),
+            Value::Array(parts) => {
+                for part in parts {
+                    text.push_str(part.get("text").and_then(Value::as_str).unwrap_or_default());
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(text)
+}
@@ -0,0 +1,106 @@
+use rustler::{Resource, ResourceArc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// How many calls and streams run at once. The others wait for a worker in the
+// order they were made.
+const WORKERS: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// The threads calls and streams run on, so that waiting for client limits,
+// retries and Elixir processes doesn't block a scheduler of the VM. The
+// threads aren't managed by the VM, so they can send messages with owned
+// environments.
+struct Pool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl Pool {
+    fn shared() -> &'static Pool {
+        static SHARED: OnceLock<Pool> = OnceLock::new();
+        SHARED.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for index in 0..WORKERS {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("baml_elixir_{}", index))
+                    .spawn(move || work(&receiver))
+                    .expect("Failed to start a BAML worker thread");
+            }
+            Pool {
+                jobs: Mutex::new(sender),
+            }
+        })
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+// Runs `job` on a worker of the pool once one is free
+pub fn spawn(job: impl FnOnce() + Send + 'static) {
+    let _ = Pool::shared().jobs.lock().unwrap().send(Box::new(job));
+}
+
+#[rustler::resource_impl()]
+impl Resource for CallResource {}
+
+// The handle of a running call, which the calling process holds on to while it
+// waits. The call is cancelled when the process cancels it, e.g. on a timeout,
+// or when the handle is garbage collected because the process died.
+pub struct CallResource {
+    pub cancellation: Arc<Cancellation>,
+}
+
+impl Drop for CallResource {
+    fn drop(&mut self) {
+        self.cancellation.cancel();
+    }
+}
+
+impl CallResource {
+    pub fn new() -> (ResourceArc<CallResource>, Arc<Cancellation>) {
+        let cancellation = Arc::new(Cancellation::default());
+        let resource = ResourceArc::new(CallResource {
+            cancellation: cancellation.clone(),
+        });
+        (resource, cancellation)
+    }
+}
+
+// Whether a call was cancelled. Messages to the calling process are sent under
+// the lock, so none are sent once `cancel` returns.
+#[derive(Default)]
+pub struct Cancellation {
+    cancelled: AtomicBool,
+    sending: Mutex<()>,
+}
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        let _sending = self.sending.lock().unwrap();
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Runs `send` unless the call was cancelled
+    pub fn send(&self, send: impl FnOnce()) {
+        let _sending = self.sending.lock().unwrap();
+        if !self.is_cancelled() {
+            send();
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use rustler::env::SavedTerm;
+use rustler::{Env, LocalPid, OwnedEnv, Resource, ResourceArc, Term};
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[rustler::resource_impl()]
+impl Resource for ReplyResource {}
+
+// Lets a call running on the worker pool ask an Elixir process for something.
+// The process gets a message with the resource and answers with the `reply`
+// NIF, once or, like for streamed responses, several times.
+pub struct ReplyResource {
+    replies: Mutex<VecDeque<(OwnedEnv, SavedTerm)>>,
+    replied: Condvar,
+}
+
+impl ReplyResource {
+    pub fn put(&self, term: Term) {
+        let owned_env = OwnedEnv::new();
+        let saved = owned_env.save(term);
+        self.replies.lock().unwrap().push_back((owned_env, saved));
+        self.replied.notify_all();
+    }
+
+    // Waits up to `timeout` for the next reply, which is handed to `decode`
+    pub fn wait<T>(
+        &self,
+        timeout: Duration,
+        decode: impl for<'a> FnOnce(Term<'a>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let deadline = Instant::now() + timeout;
+        let mut replies = self.replies.lock().unwrap();
+        loop {
+            if let Some((owned_env, saved)) = replies.pop_front() {
+                drop(replies);
+                return owned_env.run(|env| decode(saved.load(env)));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("No reply within {}ms", timeout.as_millis()));
+            }
+            replies = self
+                .replied
+                .wait_timeout(replies, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+// Sends the message built by `message`, which is given a new reply resource,
+// to `pid` and returns the resource to wait for replies on.
+pub fn send(
+    pid: LocalPid,
+    message: impl for<'a> FnOnce(Env<'a>, ResourceArc<ReplyResource>) -> Term<'a>,
+) -> Result<ResourceArc<ReplyResource>, String> {
+    let reply = ResourceArc::new(ReplyResource {
+        replies: Mutex::new(VecDeque::new()),
+        replied: Condvar::new(),
+    });
+
+    // Calls run on the worker pool, whose threads the VM doesn't manage, so
+    // owned environments can send from them
+    OwnedEnv::new()
+        .send_and_clear(&pid, |env| message(env, reply.clone()))
+        .map_err(|_| format!("Failed to send a message to {:?}", pid))?;
+
+    Ok(reply)
+}
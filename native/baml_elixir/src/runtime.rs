@@ -0,0 +1,159 @@
+use baml_runtime::BamlRuntime;
+use rustler::{Error, ListIterator, MapIterator, Resource, ResourceArc, Term};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cassette::Cassette;
+use crate::circuit_breaker::{self, CircuitBreaker};
+use crate::limits::{self, Limiter};
+use crate::orchestrator::Rotations;
+use crate::secrets::Secrets;
+use crate::{client_registry, term_to_string};
+
+// File name under which BAML source generated at runtime (e.g. retry policies
+// from a client registry or the runtime options) is loaded next to the BAML
+// source files.
+const GENERATED_FILE: &str = "baml_elixir_generated.baml";
+
+#[rustler::resource_impl()]
+impl Resource for RuntimeResource {}
+
+// A BAML runtime loaded once and shared between calls, along with the state
+// those calls share, like client limits, circuit breakers, secrets, cassettes
+// and the rotations of round-robin clients.
+pub struct RuntimeResource {
+    pub path: String,
+    pub runtime: Arc<BamlRuntime>,
+    // The source files the runtime was loaded from, generated ones included
+    pub files: Arc<HashMap<String, String>>,
+    // The retry policies declared with the runtime options
+    pub retry_policies: HashSet<String>,
+    pub limiter: Arc<Limiter>,
+    pub circuit_breaker: CircuitBreaker,
+    pub secrets: Option<Secrets>,
+    pub cassette: Option<Arc<Cassette>>,
+    pub rotations: Arc<Rotations>,
+}
+
+impl RuntimeResource {
+    pub fn new(path: String, opts: Term) -> Result<ResourceArc<RuntimeResource>, Error> {
+        let mut files = source_files(&path)?;
+
+        let mut retry_policies = HashSet::new();
+        let mut generated = String::new();
+        let mut client_limits = HashMap::new();
+        let mut circuit_breaker = None;
+        let mut secrets = None;
+        let mut cassette = None;
+        if opts.is_map() {
+            let iter =
+                MapIterator::new(opts).ok_or(Error::Term(Box::new("Invalid options map")))?;
+            for (key_term, value_term) in iter {
+                let key = term_to_string(key_term)?;
+                match key.as_str() {
+                    "retry_policies" => {
+                        let iter: ListIterator = value_term.decode().map_err(|_| {
+                            Error::Term(Box::new("Runtime retry policies must be a list"))
+                        })?;
+                        for policy_term in iter {
+                            let (name, source) =
+                                client_registry::retry_policy_from_term(policy_term)?;
+                            retry_policies.insert(name);
+                            generated.push_str(&source);
+                        }
+                    }
+                    "limits" => client_limits = limits::from_term(value_term)?,
+                    "circuit_breaker" => {
+                        circuit_breaker = Some(circuit_breaker::from_term(value_term)?)
+                    }
+                    "secrets" => secrets = Some(Secrets::from_term(value_term)?),
+                    "cassette" => cassette = Some(Arc::new(Cassette::from_term(value_term)?)),
+                    _ => {
+                        return Err(Error::Term(Box::new(format!(
+                            "Unknown runtime option: {}",
+                            key
+                        ))))
+                    }
+                }
+            }
+        }
+
+        if !generated.is_empty() {
+            files.insert(generated_file(&path), generated);
+        }
+        let runtime = from_files(&path, &files, std::env::vars().collect())?;
+
+        Ok(ResourceArc::new(RuntimeResource {
+            path,
+            runtime: Arc::new(runtime),
+            files: Arc::new(files),
+            retry_policies,
+            limiter: Arc::new(Limiter::new(client_limits)),
+            circuit_breaker: CircuitBreaker::new(circuit_breaker),
+            secrets,
+            cassette,
+            rotations: Arc::default(),
+        }))
+    }
+}
+
+// Loads the BAML source files under `path`, along with `generated` source,
+// and returns the runtime along with the files it was loaded from.
+pub fn load(
+    path: &str,
+    env_vars: HashMap<String, String>,
+    generated: Option<String>,
+) -> Result<(BamlRuntime, HashMap<String, String>), Error> {
+    let mut files = source_files(path)?;
+    if let Some(generated) = generated {
+        add_generated(path, &mut files, &generated);
+    }
+    let runtime = from_files(path, &files, env_vars)?;
+    Ok((runtime, files))
+}
+
+// Appends `generated` source to the file of generated source of `files`
+pub fn add_generated(path: &str, files: &mut HashMap<String, String>, generated: &str) {
+    files
+        .entry(generated_file(path))
+        .or_default()
+        .push_str(generated);
+}
+
+pub fn from_files(
+    path: &str,
+    files: &HashMap<String, String>,
+    env_vars: HashMap<String, String>,
+) -> Result<BamlRuntime, Error> {
+    BamlRuntime::from_file_content(path, files, env_vars)
+        .map_err(|e| Error::Term(Box::new(e.to_string())))
+}
+
+fn generated_file(path: &str) -> String {
+    Path::new(path)
+        .join(GENERATED_FILE)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Reads the BAML source files under `path`, keyed by file path.
+pub fn source_files(path: &str) -> Result<HashMap<String, String>, Error> {
+    let mut files = HashMap::new();
+    read_baml_files(Path::new(path), &mut files)
+        .map_err(|e| Error::Term(Box::new(format!("Failed to read {}: {}", path, e))))?;
+    Ok(files)
+}
+
+fn read_baml_files(dir: &Path, files: &mut HashMap<String, String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_baml_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "baml") {
+            let content = std::fs::read_to_string(&path)?;
+            files.insert(path.to_string_lossy().to_string(), content);
+        }
+    }
+    Ok(())
+}
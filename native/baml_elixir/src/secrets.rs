@@ -0,0 +1,162 @@
+use baml_types::BamlValue;
+use rustler::{Encoder, Error, LocalPid, MapIterator, NifMap, ResourceArc, Term};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::reply::{self, ReplyResource};
+use crate::{atoms, term_to_baml_value, term_to_string};
+
+#[derive(NifMap)]
+struct SecretUnavailable {
+    kind: rustler::Atom,
+    name: String,
+    message: String,
+}
+
+// Resolves the environment variables the clients of a call refer to, like
+// `env.OPENAI_API_KEY`, through an Elixir process instead of the OS environment.
+//
+// The resolver gets `{:baml_elixir_secret, reply, name}` messages and answers
+// with `{:ok, value}`, `{:ok, value, ttl_ms}` or `:error`, in which case the OS
+// environment is used. Answers are cached for their TTL.
+pub struct Secrets {
+    resolver: LocalPid,
+    ttl: Duration,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl Secrets {
+    // Parses the secrets options of a runtime resource, like:
+    // %{resolver: pid, ttl_ms: 300_000, timeout_ms: 5_000}
+    pub fn from_term(term: Term) -> Result<Secrets, Error> {
+        let iter = MapIterator::new(term).ok_or(Error::Term(Box::new("Secrets must be a map")))?;
+
+        let mut resolver = None;
+        let mut ttl = Duration::from_secs(300);
+        let mut timeout = Duration::from_secs(5);
+        for (key_term, value_term) in iter {
+            let key = term_to_string(key_term)?;
+            match key.as_str() {
+                "resolver" => {
+                    resolver = Some(
+                        value_term
+                            .decode::<LocalPid>()
+                            .map_err(|_| Error::Term(Box::new("Secrets resolver must be a pid")))?,
+                    )
+                }
+                "ttl_ms" => ttl = Duration::from_millis(positive_int(&key, value_term)?),
+                "timeout_ms" => timeout = Duration::from_millis(positive_int(&key, value_term)?),
+                _ => {
+                    return Err(Error::Term(Box::new(format!(
+                        "Unknown secrets option: {}",
+                        key
+                    ))))
+                }
+            }
+        }
+
+        Ok(Secrets {
+            resolver: resolver.ok_or(Error::Term(Box::new("Secrets need a resolver")))?,
+            ttl,
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Adds the secrets named `names` to `env_vars`. The resolver is asked for
+    // all the ones that aren't cached at once, and the answers are awaited
+    // together.
+    pub fn resolve(
+        &self,
+        names: &HashSet<String>,
+        env_vars: &mut HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut missing = Vec::new();
+        {
+            let cache = self.cache.lock().unwrap();
+            for name in names {
+                match cache.get(name).filter(|(_, expires_at)| *expires_at > now) {
+                    Some((Some(value), _)) => {
+                        env_vars.insert(name.clone(), value.clone());
+                    }
+                    Some((None, _)) => {}
+                    None => missing.push(name),
+                }
+            }
+        }
+
+        let asked: Vec<_> = missing
+            .into_iter()
+            .map(|name| (name, self.ask(name)))
+            .collect();
+        let deadline = Instant::now() + self.timeout;
+        for (name, reply) in asked {
+            let (value, ttl) = reply
+                .and_then(|reply| self.answer(&reply, deadline))
+                .map_err(|message| unavailable(name, message))?;
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(name.clone(), (value.clone(), now + ttl));
+            if let Some(value) = value {
+                env_vars.insert(name.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    fn ask(&self, name: &str) -> Result<ResourceArc<ReplyResource>, String> {
+        let message_name = name.to_string();
+        reply::send(self.resolver, move |env, reply| {
+            (atoms::baml_elixir_secret(), reply, message_name).encode(env)
+        })
+    }
+
+    fn answer(
+        &self,
+        reply: &ReplyResource,
+        deadline: Instant,
+    ) -> Result<(Option<String>, Duration), String> {
+        let default_ttl = self.ttl;
+        reply.wait(deadline.saturating_duration_since(Instant::now()), |term| {
+            if let Ok((ok, value)) = term.decode::<(rustler::Atom, String)>() {
+                if ok == atoms::ok() {
+                    return Ok((Some(value), default_ttl));
+                }
+            }
+            if let Ok((ok, value, ttl_ms)) = term.decode::<(rustler::Atom, String, u64)>() {
+                if ok == atoms::ok() {
+                    return Ok((Some(value), Duration::from_millis(ttl_ms)));
+                }
+            }
+            if term
+                .decode::<rustler::Atom>()
+                .is_ok_and(|atom| atom == atoms::error())
+            {
+                return Ok((None, default_ttl));
+            }
+            Err(format!("Invalid reply: {:?}", term))
+        })
+    }
+}
+
+fn unavailable(name: &str, message: String) -> Error {
+    Error::Term(Box::new(SecretUnavailable {
+        kind: atoms::secret_unavailable(),
+        name: name.to_string(),
+        message: format!("Failed to resolve secret {}: {}", name, message),
+    }))
+}
+
+fn positive_int(key: &str, term: Term) -> Result<u64, Error> {
+    match term_to_baml_value(term)? {
+        BamlValue::Int(n) if n > 0 => Ok(n as u64),
+        _ => Err(Error::Term(Box::new(format!(
+            "Secrets option {} must be a positive integer",
+            key
+        )))),
+    }
+}
@@ -0,0 +1,377 @@
+use baml_runtime::client_registry::ClientRegistry;
+use baml_types::BamlValue;
+use rustler::{Binary, Encoder, LocalPid, NifMap, Term};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{atoms, reply, term_to_string, Request};
+
+// A request to an LLM provider, as built by BAML for one of the clients of a
+// call. Elixir processes get it as a map.
+#[derive(Clone, NifMap)]
+pub struct HttpRequest {
+    pub client: String,
+    pub function: String,
+    pub url: String,
+    pub method: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    pub stream: bool,
+}
+
+// A response as it was received, before its text is read. The body of
+// streamed responses holds their server-sent events.
+#[derive(Clone)]
+pub struct RawResponse {
+    pub status: u16,
+    pub body: String,
+    pub stream: bool,
+}
+
+// The text of a response and the tokens it used, read the way `api` responds
+pub fn read_response(api: Api, response: &RawResponse) -> Result<(String, f64), String> {
+    if !(200..300).contains(&response.status) {
+        return Err(format!("HTTP {}: {}", response.status, response.body));
+    }
+    if !response.stream {
+        return complete_response(api, &response.body);
+    }
+    let mut events = EventReader::new(api);
+    events.feed(response.body.as_bytes(), &mut |_| {});
+    Ok(events.finish())
+}
+
+#[derive(NifMap)]
+struct ElixirResponse {
+    status: u16,
+    body: String,
+}
+
+// An Elixir process that handles the requests of a client, instead of an
+// HTTP exchange with the provider.
+#[derive(Clone, Copy)]
+pub struct Handler {
+    pub pid: LocalPid,
+    pub timeout: Duration,
+}
+
+enum HandlerReply {
+    Response(ElixirResponse),
+    Chunk(Vec<u8>),
+    Done,
+}
+
+impl Handler {
+    // Sends `request` to the handler. With `on_text`, the response is streamed
+    // and `on_text` is called with its text so far, read the way `api`
+    // responds, as it arrives.
+    pub fn send(
+        &self,
+        request: &HttpRequest,
+        api: Api,
+        mut on_text: Option<&mut dyn FnMut(&str)>,
+    ) -> Result<RawResponse, String> {
+        let mut events = EventReader::new(api);
+        self.exchange(request, &mut |chunk: &[u8]| {
+            if let Some(on_text) = on_text.as_deref_mut() {
+                events.feed(chunk, on_text);
+            }
+        })
+    }
+
+    // Sends `{:baml_elixir_request, reply, request}` to the handler, which
+    // replies with `{:ok, %{status: status, body: body}}` or `{:error, reason}`.
+    // Streamed requests can also be answered with any number of
+    // `{:chunk, data}` replies of server-sent events followed by `:done`.
+    fn exchange(
+        &self,
+        request: &HttpRequest,
+        on_chunk: &mut dyn FnMut(&[u8]),
+    ) -> Result<RawResponse, String> {
+        let message = request.clone();
+        let reply = reply::send(self.pid, move |env, reply| {
+            (atoms::baml_elixir_request(), reply, message).encode(env)
+        })?;
+
+        if !request.stream {
+            return match reply.wait(self.timeout, decode_handler_reply)? {
+                HandlerReply::Response(response) => Ok(RawResponse {
+                    status: response.status,
+                    body: response.body,
+                    stream: false,
+                }),
+                _ => Err("Chunks can only answer streamed requests".to_string()),
+            };
+        }
+
+        let mut body = Vec::new();
+        loop {
+            match reply.wait(self.timeout, decode_handler_reply)? {
+                HandlerReply::Response(response) => {
+                    if !(200..300).contains(&response.status) {
+                        return Ok(RawResponse {
+                            status: response.status,
+                            body: response.body,
+                            stream: true,
+                        });
+                    }
+                    on_chunk(response.body.as_bytes());
+                    body.extend_from_slice(response.body.as_bytes());
+                    break;
+                }
+                HandlerReply::Chunk(data) => {
+                    on_chunk(&data);
+                    body.extend_from_slice(&data);
+                }
+                HandlerReply::Done => break,
+            }
+        }
+        Ok(RawResponse {
+            status: 200,
+            body: String::from_utf8_lossy(&body).to_string(),
+            stream: true,
+        })
+    }
+}
+
+fn decode_handler_reply(term: Term) -> Result<HandlerReply, String> {
+    if term
+        .decode::<rustler::Atom>()
+        .is_ok_and(|atom| atom == atoms::done())
+    {
+        return Ok(HandlerReply::Done);
+    }
+    if let Ok((tag, value)) = term.decode::<(rustler::Atom, Term)>() {
+        if tag == atoms::ok() {
+            if let Ok(response) = value.decode::<ElixirResponse>() {
+                return Ok(HandlerReply::Response(response));
+            }
+        } else if tag == atoms::chunk() {
+            if let Ok(data) = value.decode::<Binary>() {
+                return Ok(HandlerReply::Chunk(data.as_slice().to_vec()));
+            }
+        } else if tag == atoms::error() {
+            return Err(term_to_string(value).unwrap_or_else(|_| format!("{:?}", value)));
+        }
+    }
+    Err(format!("Invalid reply: {:?}", term))
+}
+
+// The API a provider's responses follow, for the providers "elixir" clients can
+// serve
+#[derive(Clone, Copy)]
+pub enum Api {
+    // Chat completions of OpenAI and the providers compatible with it
+    OpenAI,
+    Anthropic,
+    // Gemini, through Google AI or Vertex AI
+    Google,
+}
+
+impl Api {
+    pub fn of(provider: &str) -> Option<Api> {
+        match provider {
+            "openai" | "openai-generic" | "azure-openai" | "ollama" => Some(Api::OpenAI),
+            "anthropic" => Some(Api::Anthropic),
+            "google-ai" | "vertex-ai" => Some(Api::Google),
+            _ => None,
+        }
+    }
+
+    // The text of a complete response
+    fn response_text(self, body: &Value) -> Option<String> {
+        match self {
+            Api::OpenAI => body
+                .pointer("/choices/0/message/content")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            Api::Anthropic => text_parts(body.get("content")?, true),
+            Api::Google => text_parts(body.pointer("/candidates/0/content/parts")?, false),
+        }
+    }
+
+    // The text a streamed event adds to the response
+    fn event_text(self, event: &Value) -> Option<String> {
+        match self {
+            Api::OpenAI => event
+                .pointer("/choices/0/delta/content")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            Api::Anthropic => match event.get("type").and_then(Value::as_str) {
+                Some("content_block_delta") => event
+                    .pointer("/delta/text")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                _ => None,
+            },
+            Api::Google => text_parts(event.pointer("/candidates/0/content/parts")?, false),
+        }
+    }
+
+    // The input and output tokens reported by a response or a streamed event
+    fn usage(self, body: &Value) -> (Option<i64>, Option<i64>) {
+        let (usage, input, output) = match self {
+            Api::OpenAI => (body.get("usage"), "prompt_tokens", "completion_tokens"),
+            // Streams report the input tokens in `message_start` events and
+            // the output tokens in `message_delta` ones
+            Api::Anthropic => (
+                body.get("usage").or_else(|| body.pointer("/message/usage")),
+                "input_tokens",
+                "output_tokens",
+            ),
+            Api::Google => (
+                body.get("usageMetadata"),
+                "promptTokenCount",
+                "candidatesTokenCount",
+            ),
+        };
+        let Some(usage) = usage else {
+            return (None, None);
+        };
+        (
+            usage.get(input).and_then(Value::as_i64),
+            usage.get(output).and_then(Value::as_i64),
+        )
+    }
+}
+
+// The text of the parts of a response's content, leaving out the parts that
+// aren't text, like Anthropic's tool uses
+fn text_parts(parts: &Value, typed: bool) -> Option<String> {
+    Some(
+        parts
+            .as_array()?
+            .iter()
+            .filter(|part| !typed || part.get("type").and_then(Value::as_str) == Some("text"))
+            .filter_map(|part| part.get("text").and_then(Value::as_str))
+            .collect(),
+    )
+}
+
+// The text of a complete response and the tokens it used
+fn complete_response(api: Api, body: &str) -> Result<(String, f64), String> {
+    let body: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let text = api
+        .response_text(&body)
+        .ok_or("Response has no text content")?;
+    let (input_tokens, output_tokens) = api.usage(&body);
+    Ok((
+        text,
+        (input_tokens.unwrap_or(0) + output_tokens.unwrap_or(0)) as f64,
+    ))
+}
+
+// Reads the server-sent events of a streamed response as they arrive
+struct EventReader {
+    api: Api,
+    buffer: Vec<u8>,
+    text: String,
+    input_tokens: Option<i64>,
+    output_tokens: Option<i64>,
+}
+
+impl EventReader {
+    fn new(api: Api) -> Self {
+        EventReader {
+            api,
+            buffer: Vec::new(),
+            text: String::new(),
+            input_tokens: None,
+            output_tokens: None,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], on_text: &mut dyn FnMut(&str)) {
+        self.buffer.extend_from_slice(data);
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.read_line(&String::from_utf8_lossy(&line), on_text);
+        }
+    }
+
+    fn read_line(&mut self, line: &str, on_text: &mut dyn FnMut(&str)) {
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            return;
+        };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            // e.g. OpenAI's final "[DONE]"
+            return;
+        };
+
+        let (input, output) = self.api.usage(&event);
+        self.input_tokens = input.or(self.input_tokens);
+        self.output_tokens = output.or(self.output_tokens);
+        if let Some(delta) = self.api.event_text(&event) {
+            self.text.push_str(&delta);
+            on_text(&self.text);
+        }
+    }
+
+    // Returns the text of the response and the tokens it used
+    fn finish(mut self) -> (String, f64) {
+        let rest = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).to_string();
+        self.read_line(&rest, &mut |_| {});
+        let tokens = self.input_tokens.unwrap_or(0) + self.output_tokens.unwrap_or(0);
+        (self.text, tokens as f64)
+    }
+}
+
+// Has BAML build the request of a call with `registry`, whose primary client is
+// `client`
+pub fn build_request(
+    request: &Request,
+    registry: &ClientRegistry,
+    client: &str,
+    stream: bool,
+) -> Result<HttpRequest, String> {
+    let http_request = request
+        .runtime
+        .build_request_sync(
+            request.function_name.clone(),
+            &request.params,
+            &request.ctx,
+            request.tb.as_ref(),
+            Some(registry),
+            stream,
+            request.env_vars.clone(),
+        )
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(HttpRequest {
+        client: client.to_string(),
+        function: request.function_name.clone(),
+        url: http_request.url.clone(),
+        method: http_request.method.clone(),
+        headers: http_request
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        body: http_request.body.text().unwrap_or_default().to_string(),
+        stream,
+    })
+}
+
+// Has BAML parse the text of a response to a request built by build_request
+pub fn parse(
+    request: &Request,
+    registry: &ClientRegistry,
+    text: &str,
+    allow_partials: bool,
+) -> Result<BamlValue, String> {
+    request
+        .runtime
+        .parse_llm_response(
+            request.function_name.clone(),
+            &request.params,
+            text.to_string(),
+            allow_partials,
+            &request.ctx,
+            request.tb.as_ref(),
+            Some(registry),
+            request.env_vars.clone(),
+        )
+        .map(|response| response.0.value())
+        .map_err(|e| format!("{:?}", e))
+}
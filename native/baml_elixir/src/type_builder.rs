@@ -0,0 +1,947 @@
+use baml_runtime::type_builder::TypeBuilder;
+use baml_runtime::BamlRuntime;
+use baml_types::{
+    BamlMap, BamlValue, Constraint, ConstraintLevel, FieldType, JinjaExpression, StreamingBehavior,
+};
+use indexmap::IndexSet;
+use rustler::{Error, ListIterator, MapIterator, NifMap, Resource, ResourceArc, Term};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::{atoms, field_type, runtime, term_to_baml_value, term_to_string};
+
+// Returned as `{:error, %{kind: :invalid_type_builder, ...}}` when a type
+// builder doesn't validate. `entry` and `property` point at the offending
+// class or enum and its property or value.
+#[derive(NifMap)]
+struct InvalidTypeBuilder {
+    kind: rustler::Atom,
+    entry: Option<String>,
+    property: Option<String>,
+    message: String,
+}
+
+fn invalid(entry: Option<&str>, property: Option<&str>, message: String) -> Error {
+    Error::Term(Box::new(InvalidTypeBuilder {
+        kind: atoms::invalid_type_builder(),
+        entry: entry.map(str::to_string),
+        property: property.map(str::to_string),
+        message,
+    }))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Class,
+    Enum,
+    Alias,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Class => "class",
+            Kind::Enum => "enum",
+            Kind::Alias => "type alias",
+        }
+    }
+}
+
+enum Entry<'a> {
+    Type(TypeEntry<'a>),
+    // The name of a type alias and the type it stands for
+    Alias(String, Term<'a>),
+    // BAML source with class, enum and type alias definitions, and `dynamic`
+    // blocks extending existing ones
+    Baml(String),
+}
+
+struct TypeEntry<'a> {
+    kind: Kind,
+    name: String,
+    items: Vec<Term<'a>>,
+    attributes: Option<Term<'a>>,
+}
+
+#[rustler::resource_impl()]
+impl Resource for TypeBuilderResource {}
+
+// A type builder validated once and shared between calls, which classes,
+// properties and enum values can be added to later on. Each call builds its
+// own TypeBuilder from what was added before it started, so additions don't
+// change calls already running.
+pub struct TypeBuilderResource {
+    runtime: Arc<BamlRuntime>,
+    types: Mutex<Types>,
+}
+
+impl TypeBuilderResource {
+    // `runtime` is the path to the BAML source files or a runtime resource,
+    // which the type builder is validated against, and `term` the initial
+    // entries, as for a call.
+    pub fn new(runtime: Term, term: Term) -> Result<ResourceArc<TypeBuilderResource>, Error> {
+        let runtime = match runtime.decode::<ResourceArc<runtime::RuntimeResource>>() {
+            Ok(resource) => resource.runtime.clone(),
+            Err(_) => Arc::new(
+                runtime::load(
+                    &runtime.decode::<String>()?,
+                    std::env::vars().collect(),
+                    None,
+                )?
+                .0,
+            ),
+        };
+
+        let builder = TypeBuilder::new();
+        let mut types = Types::new(&runtime);
+        if !is_nil(term) {
+            build(&builder, decode_entries(term)?, &mut types, &runtime)?;
+        }
+        Ok(ResourceArc::new(TypeBuilderResource {
+            runtime,
+            types: Mutex::new(types),
+        }))
+    }
+
+    // Builds a TypeBuilder for a call by adding again what was added so far
+    fn snapshot(&self) -> Result<TypeBuilder, Error> {
+        let types = self.types.lock().unwrap();
+        let builder = TypeBuilder::new();
+        for added in &types.added {
+            match added {
+                Added::Baml(source) => add_baml(&builder, source, &self.runtime)?,
+                Added::RecursiveAliases(cycles) => builder
+                    .recursive_type_aliases()
+                    .lock()
+                    .unwrap()
+                    .extend(cycles.iter().cloned()),
+                Added::Definition(definition) => {
+                    add_definition(&builder, definition.clone());
+                }
+            }
+        }
+        types.register_recursive_classes(&builder);
+        Ok(builder)
+    }
+
+    // Creates a class, or sets the `description` and `alias` of an existing one
+    pub fn add_class(&self, name: String, attributes: Term) -> Result<(), Error> {
+        self.add(TypeEntry {
+            kind: Kind::Class,
+            name,
+            items: Vec::new(),
+            attributes: (!is_nil(attributes)).then_some(attributes),
+        })
+    }
+
+    // Adds a property to a class, creating the class if it doesn't exist
+    pub fn add_property(&self, class: String, property: Term) -> Result<(), Error> {
+        self.add(TypeEntry {
+            kind: Kind::Class,
+            name: class,
+            items: vec![property],
+            attributes: None,
+        })
+    }
+
+    // Adds a value to an enum, creating the enum if it doesn't exist
+    pub fn add_enum_value(&self, enum_name: String, value: Term) -> Result<(), Error> {
+        self.add(TypeEntry {
+            kind: Kind::Enum,
+            name: enum_name,
+            items: vec![value],
+            attributes: None,
+        })
+    }
+
+    // The entry is validated before anything is declared or added, so that
+    // the type builder is left as it was when it doesn't validate
+    fn add(&self, entry: TypeEntry) -> Result<(), Error> {
+        let mut types = self.types.lock().unwrap();
+        let creates = types.check(&entry)?;
+        let (kind, name) = (entry.kind, entry.name.clone());
+        let definition = {
+            // A class being created can refer to itself
+            let named = |type_name: &str| match types.named(type_name) {
+                Err(_) if creates && type_name == name => Ok(FieldType::class(type_name)),
+                found => found,
+            };
+            decode_definition(entry, &named)?
+        };
+
+        if creates {
+            types.kinds.insert(name.clone(), (kind, true));
+        }
+        types.added.push(Added::Definition(definition.clone()));
+        let properties = definition.property_types();
+        if kind == Kind::Class {
+            types.add_references(&name, properties);
+            types.extended.insert(name);
+            types.update_recursive_classes();
+        }
+        Ok(())
+    }
+}
+
+// Builds the TypeBuilder of a call from a type builder resource or a list of
+// entries like:
+// [
+//   {:class, "Person", [%{name: "name", type: "string"}, %{name: "tags", type: "string[]"}]},
+//   {:class, "Address", [%{name: "zip", type: "string", description: "5 digits"}],
+//    %{description: "A postal address"}},
+//   {:enum, "Category", ["Billing", %{name: "Bug", description: "Something broke"}]},
+//   {:alias, "JsonValue", "int | string | JsonValue[] | map<string, JsonValue>"},
+//   {:baml, "class Company {\n  name string\n}"}
+// ]
+// Classes and enums that don't exist are created, and the ones marked with
+// @@dynamic get the properties or values added. Entries that don't validate,
+// extend classes or enums that aren't @@dynamic or refer to unknown types are
+// rejected.
+pub fn from_term(term: Term, runtime: &BamlRuntime) -> Result<Option<TypeBuilder>, Error> {
+    if is_nil(term) {
+        return Ok(None);
+    }
+    if let Ok(resource) = term.decode::<ResourceArc<TypeBuilderResource>>() {
+        return resource.snapshot().map(Some);
+    }
+
+    let builder = TypeBuilder::new();
+    let mut types = Types::new(runtime);
+    build(&builder, decode_entries(term)?, &mut types, runtime)?;
+    Ok(Some(builder))
+}
+
+fn is_nil(term: Term) -> bool {
+    term.decode::<rustler::Atom>()
+        .is_ok_and(|atom| atom == atoms::nil())
+}
+
+// The types a type builder can refer to: the ones of the BAML source files,
+// along with the ones the type builder creates
+struct Types {
+    // The kind of each class, enum and type alias, and whether it's @@dynamic
+    kinds: HashMap<String, (Kind, bool)>,
+    // The type each type alias stands for, which for recursive type aliases
+    // is a reference to the alias itself
+    aliases: HashMap<String, FieldType>,
+    recursive_aliases: HashMap<String, FieldType>,
+    // The classes the properties of each class refer to
+    references: HashMap<String, HashSet<String>>,
+    // The classes the type builder adds properties to
+    extended: HashSet<String>,
+    // The cycles of classes to register as recursive classes
+    recursive_classes: Vec<IndexSet<String>>,
+    // What was added to the type builder, in order
+    added: Vec<Added>,
+}
+
+// A BAML snippet, the cycles of recursive type aliases or a class or enum
+// definition added to a type builder
+enum Added {
+    Baml(String),
+    RecursiveAliases(Vec<BamlMap<String, FieldType>>),
+    Definition(Definition),
+}
+
+impl Types {
+    fn new(runtime: &BamlRuntime) -> Types {
+        let ir = &runtime.inner.ir;
+        let mut types = Types {
+            kinds: HashMap::new(),
+            aliases: HashMap::new(),
+            recursive_aliases: HashMap::new(),
+            references: HashMap::new(),
+            extended: HashSet::new(),
+            recursive_classes: Vec::new(),
+            added: Vec::new(),
+        };
+        for alias in ir.walk_type_aliases() {
+            let name = alias.name().to_string();
+            types.kinds.insert(name.clone(), (Kind::Alias, false));
+            types.aliases.insert(name, alias.target().clone());
+        }
+        for cycle in ir.structural_recursive_alias_cycles() {
+            for (name, target) in cycle {
+                types.kinds.insert(name.clone(), (Kind::Alias, false));
+                types
+                    .aliases
+                    .insert(name.clone(), FieldType::RecursiveTypeAlias(name.clone()));
+                types.recursive_aliases.insert(name.clone(), target.clone());
+            }
+        }
+        for class in ir.walk_classes() {
+            let dynamic = class.item.attributes.get("dynamic_type").is_some();
+            types
+                .kinds
+                .insert(class.name().to_string(), (Kind::Class, dynamic));
+            let properties = class.walk_fields().map(|field| field.r#type().clone());
+            types.add_references(class.name(), properties.collect());
+        }
+        for r#enum in ir.walk_enums() {
+            let dynamic = r#enum.item.attributes.get("dynamic_type").is_some();
+            types
+                .kinds
+                .insert(r#enum.name().to_string(), (Kind::Enum, dynamic));
+        }
+        types
+    }
+
+    // Checks that an entry creates a type or extends a @@dynamic one, and
+    // returns whether it creates one
+    fn check(&self, entry: &TypeEntry) -> Result<bool, Error> {
+        match self.kinds.get(&entry.name) {
+            Some((kind, _)) if *kind != entry.kind => Err(invalid(
+                Some(&entry.name),
+                None,
+                format!("{} is not a {}", entry.name, entry.kind.name()),
+            )),
+            Some((_, false)) => Err(invalid(
+                Some(&entry.name),
+                None,
+                format!("{} {} is not @@dynamic", entry.kind.name(), entry.name),
+            )),
+            Some(_) => Ok(false),
+            None => Ok(true),
+        }
+    }
+
+    fn declare(&mut self, entry: &TypeEntry) -> Result<(), Error> {
+        if self.check(entry)? {
+            self.kinds.insert(entry.name.clone(), (entry.kind, true));
+        }
+        Ok(())
+    }
+
+    fn declare_alias(&mut self, name: &str) -> Result<(), Error> {
+        if let Some((kind, _)) = self.kinds.get(name) {
+            return Err(invalid(
+                Some(name),
+                None,
+                format!("{} {} already exists", kind.name(), name),
+            ));
+        }
+        self.kinds.insert(name.to_string(), (Kind::Alias, false));
+        Ok(())
+    }
+
+    // Declares the classes and enums BAML snippets added to `builder` define
+    fn declare_baml(&mut self, builder: &TypeBuilder) {
+        let (classes, enums, ..) = builder.to_overrides();
+        for name in classes.into_keys() {
+            self.kinds.entry(name).or_insert((Kind::Class, true));
+        }
+        for name in enums.into_keys() {
+            self.kinds.entry(name).or_insert((Kind::Enum, true));
+        }
+    }
+
+    // Type aliases declared but not defined yet are referred to by name
+    fn named(&self, name: &str) -> Result<FieldType, String> {
+        match self.kinds.get(name) {
+            Some((Kind::Class, _)) => Ok(FieldType::class(name)),
+            Some((Kind::Enum, _)) => Ok(FieldType::r#enum(name)),
+            Some((Kind::Alias, _)) => Ok(self
+                .aliases
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| FieldType::RecursiveTypeAlias(name.to_string()))),
+            None => Err(format!("Unknown type {}", name)),
+        }
+    }
+
+    // Defines type aliases from the types they stand for, in which the
+    // aliases are referred to by name. Aliases that don't refer to themselves,
+    // directly or through other aliases, are replaced by their type wherever
+    // they are used, and the cycles of the others are returned.
+    fn define_aliases(
+        &mut self,
+        targets: Vec<(String, FieldType)>,
+    ) -> Vec<BamlMap<String, FieldType>> {
+        let targets: HashMap<String, FieldType> = targets.into_iter().collect();
+        let graph: HashMap<String, HashSet<String>> = targets
+            .iter()
+            .map(|(name, target)| {
+                let mut references = HashSet::new();
+                field_type::walk(target, &mut |inner| {
+                    if let FieldType::RecursiveTypeAlias(alias) = inner {
+                        if targets.contains_key(alias) {
+                            references.insert(alias.clone());
+                        }
+                    }
+                });
+                (name.clone(), references)
+            })
+            .collect();
+        let alias_cycles = cycles(&graph);
+        let recursive: HashSet<String> = alias_cycles.iter().flatten().cloned().collect();
+
+        fn expand(
+            target: &FieldType,
+            targets: &HashMap<String, FieldType>,
+            recursive: &HashSet<String>,
+        ) -> FieldType {
+            field_type::replace_aliases(target, &|alias| match targets.get(alias) {
+                Some(target) if !recursive.contains(alias) => {
+                    Some(expand(target, targets, recursive))
+                }
+                _ => None,
+            })
+        }
+        for (name, target) in &targets {
+            let target = expand(target, &targets, &recursive);
+            if recursive.contains(name) {
+                self.recursive_aliases.insert(name.clone(), target);
+                self.aliases
+                    .insert(name.clone(), FieldType::RecursiveTypeAlias(name.clone()));
+            } else {
+                self.aliases.insert(name.clone(), target);
+            }
+        }
+
+        alias_cycles
+            .into_iter()
+            .map(|cycle| {
+                cycle
+                    .into_iter()
+                    .map(|name| {
+                        let target = self.recursive_aliases[&name].clone();
+                        (name, target)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Adds the classes the types of the properties of a class refer to,
+    // including through recursive type aliases
+    fn add_references(&mut self, class: &str, properties: Vec<FieldType>) {
+        let mut references = HashSet::new();
+        let mut aliases = HashSet::new();
+        let mut pending = properties;
+        while let Some(property) = pending.pop() {
+            field_type::walk(&property, &mut |inner| match inner {
+                FieldType::Class(name) => {
+                    references.insert(name.clone());
+                }
+                FieldType::RecursiveTypeAlias(alias) => {
+                    if let Some(target) = self.recursive_aliases.get(alias) {
+                        if aliases.insert(alias.clone()) {
+                            pending.push(target.clone());
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+        self.references
+            .entry(class.to_string())
+            .or_default()
+            .extend(references);
+    }
+
+    // Finds the cycles of classes referring to each other that go through a
+    // class the type builder adds properties to, which BAML renders and parses
+    // as recursive classes
+    fn update_recursive_classes(&mut self) {
+        self.recursive_classes = cycles(&self.references)
+            .into_iter()
+            .filter(|cycle| cycle.iter().any(|class| self.extended.contains(class)))
+            .map(|cycle| cycle.into_iter().collect())
+            .collect();
+    }
+
+    fn register_recursive_classes(&self, builder: &TypeBuilder) {
+        builder
+            .recursive_classes()
+            .lock()
+            .unwrap()
+            .extend(self.recursive_classes.iter().cloned());
+    }
+}
+
+// The groups of names of `graph` that reach each other through its edges,
+// for the names that reach themselves
+fn cycles(graph: &HashMap<String, HashSet<String>>) -> Vec<Vec<String>> {
+    let reachable = |from: &String| {
+        let mut reached = HashSet::new();
+        let mut pending: Vec<&String> = graph.get(from).into_iter().flatten().collect();
+        while let Some(name) = pending.pop() {
+            if reached.insert(name.clone()) {
+                pending.extend(graph.get(name).into_iter().flatten());
+            }
+        }
+        reached
+    };
+    let mut names: Vec<&String> = graph.keys().collect();
+    names.sort();
+    let reached: HashMap<&String, HashSet<String>> =
+        names.iter().map(|name| (*name, reachable(*name))).collect();
+
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    for name in &names {
+        if !reached[name].contains(*name) || cycles.iter().flatten().any(|n| n == *name) {
+            continue;
+        }
+        let cycle = names
+            .iter()
+            .filter(|other| reached[name].contains(**other) && reached[**other].contains(*name))
+            .map(|other| other.to_string())
+            .collect();
+        cycles.push(cycle);
+    }
+    cycles
+}
+
+fn decode_entries(term: Term) -> Result<Vec<Entry>, Error> {
+    let iter: ListIterator = term.decode().map_err(|_| {
+        invalid(
+            None,
+            None,
+            "Type builder must be nil or a list of classes, enums, type aliases and BAML snippets"
+                .to_string(),
+        )
+    })?;
+    iter.map(decode_entry).collect()
+}
+
+// Adds the BAML snippets to `builder` and declares the types of all entries
+// first, so that they can refer to each other regardless of their order, then
+// defines the type aliases and adds the other entries
+fn build(
+    builder: &TypeBuilder,
+    entries: Vec<Entry>,
+    types: &mut Types,
+    runtime: &BamlRuntime,
+) -> Result<(), Error> {
+    for entry in &entries {
+        if let Entry::Baml(source) = entry {
+            add_baml(builder, source, runtime)?;
+            types.added.push(Added::Baml(source.clone()));
+        }
+    }
+    types.declare_baml(builder);
+    for entry in &entries {
+        match entry {
+            Entry::Type(entry) => types.declare(entry)?,
+            Entry::Alias(name, _) => types.declare_alias(name)?,
+            Entry::Baml(_) => {}
+        }
+    }
+
+    let mut targets = Vec::new();
+    for entry in &entries {
+        if let Entry::Alias(name, term) = entry {
+            let named = |type_name: &str| types.named(type_name);
+            let target = field_type::from_term(*term, &named).map_err(|e| {
+                invalid(
+                    Some(name),
+                    None,
+                    format!("Invalid type of type alias {}: {}", name, e),
+                )
+            })?;
+            targets.push((name.clone(), target));
+        }
+    }
+    let alias_cycles = types.define_aliases(targets);
+    builder
+        .recursive_type_aliases()
+        .lock()
+        .unwrap()
+        .extend(alias_cycles.iter().cloned());
+    types.added.push(Added::RecursiveAliases(alias_cycles));
+
+    // The classes and enums of the entries are all validated before any of
+    // them is added
+    let named = |name: &str| types.named(name);
+    let mut definitions = Vec::new();
+    for entry in entries {
+        if let Entry::Type(entry) = entry {
+            definitions.push(decode_definition(entry, &named)?);
+        }
+    }
+
+    let mut properties = Vec::new();
+    for definition in definitions {
+        let (kind, name) = (definition.kind, definition.name.clone());
+        types.added.push(Added::Definition(definition.clone()));
+        let class_properties = add_definition(builder, definition);
+        if kind == Kind::Class {
+            properties.push((name, class_properties));
+        }
+    }
+
+    for (class, class_properties) in properties {
+        types.add_references(&class, class_properties);
+        types.extended.insert(class);
+    }
+    types.update_recursive_classes();
+    types.register_recursive_classes(builder);
+    Ok(())
+}
+
+fn add_baml(builder: &TypeBuilder, source: &str, runtime: &BamlRuntime) -> Result<(), Error> {
+    builder
+        .add_baml(source, &runtime.inner)
+        .map_err(|e| invalid(None, None, format!("Invalid BAML in type builder: {}", e)))
+}
+
+// Entries are `{:class, name, fields}`, `{:class, name, fields, attributes}`,
+// `{:enum, name, values}`, `{:alias, name, type}` or `{:baml, source}`
+fn decode_entry(term: Term) -> Result<Entry, Error> {
+    let invalid_entry = || {
+        invalid(
+            None,
+            None,
+            format!("Invalid type builder entry: {:?}", term),
+        )
+    };
+    let items = rustler::types::tuple::get_tuple(term).map_err(|_| invalid_entry())?;
+    if let [kind, source] = items.as_slice() {
+        return match (term_to_string(*kind).as_deref(), source.decode::<String>()) {
+            (Ok("baml"), Ok(source)) => Ok(Entry::Baml(source)),
+            _ => Err(invalid_entry()),
+        };
+    }
+    if let [kind, name, target] = items.as_slice() {
+        if term_to_string(*kind).as_deref() == Ok("alias") {
+            let name = name.decode::<String>().map_err(|_| invalid_entry())?;
+            return Ok(Entry::Alias(name, *target));
+        }
+    }
+    let (kind, name, list, attributes) = match items.as_slice() {
+        [kind, name, list] => (*kind, *name, *list, None),
+        [kind, name, list, attributes] => (*kind, *name, *list, Some(*attributes)),
+        _ => return Err(invalid_entry()),
+    };
+
+    let kind = match term_to_string(kind).as_deref() {
+        Ok("class") => Kind::Class,
+        Ok("enum") if attributes.is_none() => Kind::Enum,
+        _ => return Err(invalid_entry()),
+    };
+    let name = name.decode::<String>().map_err(|_| invalid_entry())?;
+    let items = list.decode::<Vec<Term>>().map_err(|_| {
+        let items = match kind {
+            Kind::Class => "fields",
+            _ => "values",
+        };
+        invalid(
+            Some(&name),
+            None,
+            format!("The {} of {} {} must be a list", items, kind.name(), name),
+        )
+    })?;
+
+    Ok(Entry::Type(TypeEntry {
+        kind,
+        name,
+        items,
+        attributes,
+    }))
+}
+
+// A class or enum entry that validated, with the attributes of the type and
+// the properties or values to add to it
+#[derive(Clone)]
+struct Definition {
+    kind: Kind,
+    name: String,
+    meta: Vec<(String, BamlValue)>,
+    items: Vec<Item>,
+}
+
+// A property, with its type, or an enum value
+#[derive(Clone)]
+struct Item {
+    name: String,
+    field_type: Option<FieldType>,
+    meta: Vec<(String, BamlValue)>,
+}
+
+fn decode_definition(
+    entry: TypeEntry,
+    named: &dyn Fn(&str) -> Result<FieldType, String>,
+) -> Result<Definition, Error> {
+    match entry.kind {
+        Kind::Class => decode_class(entry, named),
+        _ => decode_enum(entry),
+    }
+}
+
+impl Definition {
+    fn property_types(&self) -> Vec<FieldType> {
+        self.items
+            .iter()
+            .filter_map(|item| item.field_type.clone())
+            .collect()
+    }
+}
+
+// Adds a definition to `builder`, returning the types of the properties
+fn add_definition(builder: &TypeBuilder, definition: Definition) -> Vec<FieldType> {
+    let mut properties = Vec::new();
+    if definition.kind == Kind::Enum {
+        let enm = builder.r#enum(&definition.name);
+        let enm = enm.lock().unwrap();
+        for item in definition.items {
+            let value = enm.value(&item.name);
+            let value = value.lock().unwrap();
+            for (key, meta_value) in item.meta {
+                value.with_meta(&key, meta_value);
+            }
+        }
+        return properties;
+    }
+
+    let cls = builder.class(&definition.name);
+    let cls = cls.lock().unwrap();
+    for (key, value) in definition.meta {
+        cls.with_meta(&key, value);
+    }
+    for item in definition.items {
+        let property = cls.property(&item.name);
+        let property = property.lock().unwrap();
+        if let Some(field_type) = item.field_type {
+            property.r#type(field_type.clone());
+            properties.push(field_type);
+        }
+        for (key, value) in item.meta {
+            property.with_meta(&key, value);
+        }
+    }
+    properties
+}
+
+// Each field is a map with the name of the property and its type, which is a
+// BAML type expression or a type as described by `parse_baml`, along with
+// optional attributes:
+// %{
+//   name: "age",
+//   type: "int",
+//   description: "Age in years",
+//   alias: "years",
+//   checks: [%{name: "adult", expression: "this >= 18"}],
+//   asserts: ["this >= 0"],
+//   skip: false
+// }
+// The class itself can have a `description` and an `alias`.
+fn decode_class(
+    entry: TypeEntry,
+    named: &dyn Fn(&str) -> Result<FieldType, String>,
+) -> Result<Definition, Error> {
+    let name = entry.name.as_str();
+
+    let mut class_meta = Vec::new();
+    if let Some(attributes) = entry.attributes {
+        let iter = MapIterator::new(attributes).ok_or(invalid(
+            Some(name),
+            None,
+            format!("Attributes of class {} must be a map", name),
+        ))?;
+        for (key_term, value_term) in iter {
+            let key = term_to_string(key_term)?;
+            match (key.as_str(), term_to_baml_value(value_term)?) {
+                ("description" | "alias", value @ BamlValue::String(_)) => {
+                    class_meta.push((key, value));
+                }
+                _ => {
+                    return Err(invalid(
+                        Some(name),
+                        None,
+                        format!("Invalid attribute {} of class {}", key, name),
+                    ))
+                }
+            }
+        }
+    }
+
+    let mut items = Vec::new();
+    for field_term in entry.items {
+        let field_iter = MapIterator::new(field_term).ok_or(invalid(
+            Some(name),
+            None,
+            format!("Fields of class {} must be maps", name),
+        ))?;
+
+        let mut field_name = None;
+        let mut type_term = None;
+        let mut constraints = Vec::new();
+        let mut meta_terms = Vec::new();
+        for (key_term, value_term) in field_iter {
+            let key = term_to_string(key_term)?;
+            match key.as_str() {
+                "name" => field_name = value_term.decode::<String>().ok(),
+                "type" => type_term = Some(value_term),
+                "checks" => constraints.push((ConstraintLevel::Check, value_term)),
+                "asserts" => constraints.push((ConstraintLevel::Assert, value_term)),
+                _ => meta_terms.push((key, value_term)),
+            }
+        }
+
+        let field_name = field_name.ok_or(invalid(
+            Some(name),
+            None,
+            format!("A property of class {} is missing a name", name),
+        ))?;
+        let invalid_property = |message: String| {
+            invalid(
+                Some(name),
+                Some(&field_name),
+                format!(
+                    "Invalid property {} of class {}: {}",
+                    field_name, name, message
+                ),
+            )
+        };
+
+        let type_term = type_term.ok_or_else(|| invalid_property("missing a type".to_string()))?;
+        let mut field_type = field_type::from_term(type_term, named).map_err(|e| {
+            invalid(
+                Some(name),
+                Some(&field_name),
+                format!(
+                    "Invalid type of property {} of class {}: {}",
+                    field_name, name, e
+                ),
+            )
+        })?;
+
+        let constraints = constraints
+            .into_iter()
+            .map(|(level, term)| decode_constraints(level, term))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_property)?
+            .concat();
+        if !constraints.is_empty() {
+            field_type = FieldType::WithMetadata {
+                base: Box::new(field_type),
+                constraints,
+                streaming_behavior: StreamingBehavior::default(),
+            };
+        }
+
+        let mut meta = Vec::new();
+        for (key, value_term) in meta_terms {
+            match (key.as_str(), term_to_baml_value(value_term)?) {
+                ("description" | "alias", value @ BamlValue::String(_)) => meta.push((key, value)),
+                ("skip", value @ BamlValue::Bool(_)) => meta.push((key, value)),
+                _ => return Err(invalid_property(format!("invalid attribute {}", key))),
+            }
+        }
+        items.push(Item {
+            name: field_name,
+            field_type: Some(field_type),
+            meta,
+        });
+    }
+
+    Ok(Definition {
+        kind: Kind::Class,
+        name: entry.name,
+        meta: class_meta,
+        items,
+    })
+}
+
+// Checks and asserts are lists of Jinja expressions about `this`, given as
+// strings or as maps with the `expression` and a `name`, which checks need.
+fn decode_constraints(level: ConstraintLevel, term: Term) -> Result<Vec<Constraint>, String> {
+    let kind = match level {
+        ConstraintLevel::Check => "checks",
+        ConstraintLevel::Assert => "asserts",
+    };
+    let items = term
+        .decode::<Vec<Term>>()
+        .map_err(|_| format!("{} must be a list", kind))?;
+
+    items
+        .into_iter()
+        .map(|item| {
+            let (label, expression) = match term_to_baml_value(item) {
+                Ok(BamlValue::String(expression)) => (None, expression),
+                Ok(BamlValue::Map(mut map)) => {
+                    match (map.shift_remove("name"), map.shift_remove("expression")) {
+                        (Some(BamlValue::String(name)), Some(BamlValue::String(expression)))
+                            if map.is_empty() =>
+                        {
+                            (Some(name), expression)
+                        }
+                        (None, Some(BamlValue::String(expression))) if map.is_empty() => {
+                            (None, expression)
+                        }
+                        _ => return Err(format!("invalid {}: {:?}", kind, item)),
+                    }
+                }
+                _ => return Err(format!("invalid {}: {:?}", kind, item)),
+            };
+            if label.is_none() && matches!(level, ConstraintLevel::Check) {
+                return Err("checks need a name".to_string());
+            }
+            Ok(Constraint {
+                level: level.clone(),
+                expression: JinjaExpression(expression),
+                label,
+            })
+        })
+        .collect()
+}
+
+// Each value is its name, or a map with the name and optionally a
+// `description`, an `alias` the model sees instead of the name, and `skip` to
+// leave the value out of the prompt and the results.
+fn decode_enum(entry: TypeEntry) -> Result<Definition, Error> {
+    let name = entry.name.as_str();
+
+    let mut items = Vec::new();
+    for value_term in entry.items {
+        if let Ok(value_name) = value_term.decode::<String>() {
+            items.push(Item {
+                name: value_name,
+                field_type: None,
+                meta: Vec::new(),
+            });
+            continue;
+        }
+
+        let iter = MapIterator::new(value_term).ok_or(invalid(
+            Some(name),
+            None,
+            format!("Values of enum {} must be strings or maps", name),
+        ))?;
+        let mut value_name = None;
+        let mut meta = Vec::new();
+        for (key_term, meta_term) in iter {
+            let key = term_to_string(key_term)?;
+            match (key.as_str(), term_to_baml_value(meta_term)?) {
+                ("name", BamlValue::String(value)) => value_name = Some(value),
+                ("description" | "alias", value @ BamlValue::String(_)) => meta.push((key, value)),
+                ("skip", value @ BamlValue::Bool(_)) => meta.push((key, value)),
+                _ => {
+                    return Err(invalid(
+                        Some(name),
+                        None,
+                        format!("Invalid {} of a value of enum {}", key, name),
+                    ))
+                }
+            }
+        }
+
+        let value_name = value_name.ok_or(invalid(
+            Some(name),
+            None,
+            format!("A value of enum {} is missing a name", name),
+        ))?;
+        items.push(Item {
+            name: value_name,
+            field_type: None,
+            meta,
+        });
+    }
+
+    Ok(Definition {
+        kind: Kind::Enum,
+        name: entry.name,
+        meta: Vec::new(),
+        items,
+    })
+}
@@ -27,10 +27,9 @@ defmodule BamlElixirTest do
 
   test "parses into a struct with a type builder" do
     assert {:ok,
-            %{
-              __baml_class__: "NewEmployee",
+            %BamlElixirTest.NewEmployee{
               employee_id: _,
-              person: %{name: _, age: _, __baml_class__: "TestPerson"}
+              __dynamic__: %{person: %{name: _, age: _, __baml_class__: "TestPerson"}}
             }} =
              BamlElixirTest.CreateEmployee.call(%{}, %{
                tb: [
@@ -41,11 +40,1098 @@ defmodule BamlElixirTest do
              })
   end
 
+  test "keeps the struct of a dynamic class without a type builder" do
+    assert {:ok, %BamlElixirTest.NewEmployee{employee_id: _, __dynamic__: %{}}} =
+             BamlElixirTest.CreateEmployee.call(%{})
+  end
+
+  test "add values to a dynamic enum with a type builder" do
+    assert {:ok, :Billing} =
+             BamlElixirTest.ClassifyTicket.call(%{ticket: "I was charged twice this month"}, %{
+               tb: [
+                 {:enum, "Category",
+                  [
+                    %{name: "Billing", description: "Payments, invoices and refunds"},
+                    %{name: "Bug", alias: "defect"},
+                    %{name: "Legacy", skip: true}
+                  ]}
+               ]
+             })
+  end
+
+  test "use BAML type expressions in a type builder" do
+    assert {:ok,
+            %BamlElixirTest.NewEmployee{
+              __dynamic__: %{skills: skills, level: level, ratings: ratings}
+            }} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{
+               tb: [
+                 {:class, "NewEmployee",
+                  [
+                    %{name: "skills", type: "string[]"},
+                    %{name: "manager_id", type: "string?"},
+                    %{name: "level", type: ~s("junior" | "senior")},
+                    %{name: "ratings", type: "map<string, int>"},
+                    %{name: "years", type: {:list, {:primitive, :integer}}}
+                  ]}
+               ]
+             })
+
+    assert Enum.all?(skills, &is_binary/1)
+    assert level in ["junior", "senior"]
+    assert Enum.all?(ratings, fn {key, value} -> is_binary(key) and is_integer(value) end)
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "NewEmployee",
+              property: "skills",
+              message: "Invalid type of property skills of class NewEmployee: Expected ] but" <> _
+            }} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{
+               tb: [{:class, "NewEmployee", [%{name: "skills", type: "string[int"}]}]
+             })
+  end
+
+  test "reject invalid type builders" do
+    call = fn tb -> BamlElixirTest.CreateEmployee.call(%{}, %{tb: tb}) end
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: nil,
+              message: "Invalid type builder entry" <> _
+            }} = call.([{:class, "NewEmployee"}])
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "DummyOutput",
+              message: "class DummyOutput is not @@dynamic"
+            }} = call.([{:class, "DummyOutput", [%{name: "extra", type: "string"}]}])
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "NewEmployee",
+              property: "person",
+              message: "Invalid type of property person of class NewEmployee: Unknown type Persn"
+            }} = call.([{:class, "NewEmployee", [%{name: "person", type: "Persn"}]}])
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "NewEmployee",
+              message: "Fields of class NewEmployee must be maps"
+            }} = call.([{:class, "NewEmployee", ["person"]}])
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "Model",
+              message: "enum Model is not @@dynamic"
+            }} = call.([{:enum, "Model", ["Claude"]}])
+  end
+
+  test "describe and constrain type builder properties" do
+    tb = fn age_asserts ->
+      [
+        {:class, "TestPerson",
+         [
+           %{name: "name", type: "string", description: "Full name", alias: "full_name"},
+           %{
+             name: "age",
+             type: "int",
+             checks: [%{name: "adult", expression: "this >= 18"}],
+             asserts: age_asserts
+           },
+           %{name: "notes", type: "string?", skip: true}
+         ], %{description: "A person working at the company"}},
+        {:class, "NewEmployee", [%{name: "person", type: "TestPerson"}]}
+      ]
+    end
+
+    assert {:ok, %{__dynamic__: %{person: %{name: _, age: _} = person}}} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{tb: tb.(["this > 0"])})
+
+    refute Map.has_key?(person, :notes)
+
+    assert {:error, _} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{tb: tb.(["this > 1000"])})
+  end
+
+  test "define type builder types with BAML snippets" do
+    assert {:ok,
+            %BamlElixirTest.NewEmployee{
+              __dynamic__: %{
+                person: %{name: _, age: _, __baml_class__: "TestPerson"},
+                department: _
+              }
+            }} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{
+               tb: [
+                 {:baml,
+                  """
+                  class TestPerson {
+                    name string
+                    age int @description("Age in years")
+                  }
+
+                  dynamic class NewEmployee {
+                    person TestPerson
+                  }
+                  """},
+                 {:class, "NewEmployee", [%{name: "department", type: "string"}]}
+               ]
+             })
+
+    assert {:error,
+            %{kind: :invalid_type_builder, message: "Invalid BAML in type builder: " <> _}} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{tb: [{:baml, "class {"}]})
+  end
+
+  test "define recursive type aliases and classes with a type builder" do
+    assert {:ok,
+            %BamlElixirTest.NewEmployee{__dynamic__: %{thread: thread, metadata: metadata}}} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{
+               tb: [
+                 {:alias, "JsonValue",
+                  "int | float | bool | string | null | JsonValue[] | map<string, JsonValue>"},
+                 {:class, "Comment",
+                  [%{name: "text", type: "string"}, %{name: "replies", type: "Comment[]"}]},
+                 {:class, "NewEmployee",
+                  [
+                    %{name: "thread", type: "Comment", description: "A thread of 2 comments"},
+                    %{name: "metadata", type: "JsonValue"}
+                  ]}
+               ]
+             })
+
+    assert %{__baml_class__: "Comment", text: _, replies: replies} = thread
+    assert Enum.all?(replies, &match?(%{__baml_class__: "Comment"}, &1))
+    assert is_map(metadata) or is_list(metadata) or is_binary(metadata) or is_number(metadata)
+
+    assert {:error,
+            %{
+              kind: :invalid_type_builder,
+              entry: "NewEmployee",
+              message: "class NewEmployee already exists"
+            }} =
+             BamlElixirTest.CreateEmployee.call(%{}, %{tb: [{:alias, "NewEmployee", "string"}]})
+  end
+
+  test "build a type builder from a JSON Schema" do
+    schema = """
+    {
+      "type": "object",
+      "properties": {
+        "person": {"$ref": "#/$defs/contact", "description": "The new employee"},
+        "level": {"enum": ["junior", "senior"]},
+        "skills": {"type": "array", "items": {"type": "string"}},
+        "start-date": {"type": "string"}
+      },
+      "required": ["person", "level", "skills"],
+      "$defs": {
+        "contact": {
+          "type": "object",
+          "properties": {"name": {"type": "string"}, "age": {"type": ["integer", "null"]}},
+          "required": ["name"]
+        }
+      }
+    }
+    """
+
+    assert {:ok, entries} = BamlElixir.TypeBuilder.from_json_schema(schema, name: "NewEmployee")
+    assert {:enum, "NewEmployeeLevel", ["junior", "senior"]} in entries
+    assert {:class, "Contact", contact} = List.keyfind(entries, "Contact", 1)
+    assert %{name: "age", type: "(int | null)?"} in contact
+    assert {:class, "NewEmployee", employee} = List.keyfind(entries, "NewEmployee", 1)
+    assert %{name: "start_date", alias: "start-date", type: "string?"} in employee
+
+    assert {:ok,
+            %BamlElixirTest.NewEmployee{
+              __dynamic__: %{
+                person: %{__baml_class__: "Contact", name: _},
+                level: level,
+                skills: skills
+              }
+            }} = BamlElixirTest.CreateEmployee.call(%{}, %{tb: entries})
+
+    assert level in [:junior, :senior]
+    assert is_list(skills)
+
+    assert {:error,
+            %{
+              kind: :invalid_json_schema,
+              path: "#/properties/x",
+              message: "Unsupported $ref https://example.com/x.json"
+            }} =
+             BamlElixir.TypeBuilder.from_json_schema(%{
+               "title" => "Thing",
+               "type" => "object",
+               "properties" => %{"x" => %{"$ref" => "https://example.com/x.json"}}
+             })
+  end
+
+  test "preview the output format of a function with a type builder" do
+    assert {:ok, output_format} = BamlElixirTest.CreateEmployee.output_format()
+    assert output_format =~ "employee_id"
+    refute output_format =~ "department"
+
+    assert {:ok, output_format} =
+             BamlElixirTest.CreateEmployee.output_format(%{
+               tb: [
+                 {:class, "NewEmployee",
+                  [%{name: "department", type: "string", description: "The team they join"}]}
+               ]
+             })
+
+    assert output_format =~ "department"
+    assert output_format =~ "The team they join"
+
+    assert {:error, "Unknown function Missing"} =
+             BamlElixir.Client.output_format("Missing", %{path: "test/baml_src"})
+  end
+
+  test "preview the output format of functions returning checked and literal types" do
+    assert {:ok, output_format} = BamlElixirTest.RateUrgency.output_format()
+    assert output_format =~ "int"
+    refute output_format =~ "in_range"
+
+    assert {:ok, output_format} = BamlElixirTest.AnswerYesOrNo.output_format()
+    assert output_format =~ ~s("yes")
+    assert output_format =~ ~s("no")
+  end
+
+  test "preview the output format with a runtime that declares retry policies" do
+    retry_policy = %{name: "Twice", max_retries: 2, strategy: "constant_delay", delay_ms: 10}
+    {:ok, runtime} = BamlElixir.Runtime.new("test/baml_src", retry_policies: [retry_policy])
+
+    assert {:ok, tb} =
+             BamlElixir.TypeBuilder.new(
+               [{:class, "NewEmployee", [%{name: "department", type: "string"}]}],
+               runtime: runtime
+             )
+
+    assert {:ok, output_format} =
+             BamlElixirTest.CreateEmployee.output_format(%{runtime: runtime, tb: tb})
+
+    assert output_format =~ "employee_id"
+    assert output_format =~ "department"
+  end
+
+  test "reuse a type builder resource across calls" do
+    assert {:ok, tb} =
+             BamlElixir.TypeBuilder.new(
+               [{:class, "TestPerson", [%{name: "name", type: "string"}]}],
+               path: "test/baml_src"
+             )
+
+    assert :ok =
+             BamlElixir.TypeBuilder.add_property(tb, "TestPerson", %{name: "age", type: "int"})
+
+    assert :ok =
+             BamlElixir.TypeBuilder.add_property(tb, "NewEmployee", %{
+               name: "person",
+               type: "TestPerson"
+             })
+
+    for _ <- 1..2 do
+      assert {:ok,
+              %BamlElixirTest.NewEmployee{
+                __dynamic__: %{person: %{name: _, age: _, __baml_class__: "TestPerson"}}
+              }} = BamlElixirTest.CreateEmployee.call(%{}, %{tb: tb})
+    end
+
+    assert :ok = BamlElixir.TypeBuilder.add_class(tb, "TestPerson", %{description: "A person"})
+    assert :ok = BamlElixir.TypeBuilder.add_enum_value(tb, "Category", "Billing")
+
+    assert {:ok, :Billing} =
+             BamlElixirTest.ClassifyTicket.call(%{ticket: "I was charged twice this month"}, %{
+               tb: tb
+             })
+
+    assert {:error, %{kind: :invalid_type_builder, entry: "DummyOutput"}} =
+             BamlElixir.TypeBuilder.add_property(tb, "DummyOutput", %{name: "x", type: "string"})
+
+    assert {:error, %{kind: :invalid_type_builder, property: "boss"}} =
+             BamlElixir.TypeBuilder.add_property(tb, "NewEmployee", %{
+               name: "boss",
+               type: "Persn"
+             })
+
+    # Entries that don't validate leave the type builder as it was
+    assert {:error, %{kind: :invalid_type_builder, entry: "NewThing"}} =
+             BamlElixir.TypeBuilder.add_property(tb, "NewThing", %{name: "x", type: "Persn"})
+
+    assert :ok = BamlElixir.TypeBuilder.add_enum_value(tb, "NewThing", "Billing")
+  end
+
+  test "add to a type builder resource while a call using it runs" do
+    assert {:ok, tb} =
+             BamlElixir.TypeBuilder.new(
+               [
+                 {:class, "TestPerson", [%{name: "name", type: "string"}]},
+                 {:class, "NewEmployee", [%{name: "person", type: "TestPerson"}]}
+               ],
+               path: "test/baml_src"
+             )
+
+    opts = %{
+      tb: tb,
+      client_registry: %{
+        primary: "InProcess",
+        clients: [
+          %{name: "InProcess", provider: "elixir", handler: self(), options: %{model: "fake"}}
+        ]
+      }
+    }
+
+    content = ~s({"employee_id": "1", "person": {"name": "Ada", "badge_number": 7}})
+    body = Jason.encode!(%{choices: [%{message: %{content: content}}]})
+
+    task = Task.async(fn -> BamlElixirTest.CreateEmployee.call(%{}, opts) end)
+    assert_receive {:baml_elixir_request, reply, %{body: request_body}}, 5_000
+    refute request_body =~ "badge_number"
+
+    assert :ok =
+             BamlElixir.TypeBuilder.add_property(tb, "TestPerson", %{
+               name: "badge_number",
+               type: "int"
+             })
+
+    # The running call keeps the type builder it started with
+    BamlElixir.Runtime.reply(reply, {:ok, %{status: 200, body: body}})
+
+    assert {:ok, %BamlElixirTest.NewEmployee{__dynamic__: %{person: person}}} =
+             Task.await(task)
+
+    refute Map.has_key?(person, :badge_number)
+
+    task = Task.async(fn -> BamlElixirTest.CreateEmployee.call(%{}, opts) end)
+    assert_receive {:baml_elixir_request, reply, %{body: request_body}}, 5_000
+    assert request_body =~ "badge_number"
+    BamlElixir.Runtime.reply(reply, {:ok, %{status: 200, body: body}})
+
+    assert {:ok, %BamlElixirTest.NewEmployee{__dynamic__: %{person: %{badge_number: 7}}}} =
+             Task.await(task)
+  end
+
   test "change default model" do
     assert BamlElixirTest.WhichModel.call(%{}, %{llm_client: "GPT4"}) == {:ok, :GPT4oMini}
     assert BamlElixirTest.WhichModel.call(%{}, %{llm_client: "DeepSeekR1"}) == {:ok, :DeepSeekR1}
   end
 
+  test "use a provider/model shorthand as the LLM client" do
+    assert BamlElixirTest.WhichModel.call(%{}, %{llm_client: "openai/gpt-4o-mini"}) ==
+             {:ok, :GPT4oMini}
+  end
+
+  test "define a client at runtime with the client registry" do
+    assert BamlElixirTest.WhichModel.call(%{}, %{
+             client_registry: %{
+               primary: "RuntimeGPT4oMini",
+               clients: [
+                 %{
+                   name: "RuntimeGPT4oMini",
+                   provider: "openai",
+                   options: %{
+                     model: "gpt-4o-mini",
+                     api_key: System.get_env("OPENAI_API_KEY"),
+                     temperature: 0.0
+                   }
+                 }
+               ]
+             }
+           }) == {:ok, :GPT4oMini}
+  end
+
+  test "use a runtime-defined fallback client as the primary" do
+    assert BamlElixirTest.WhichModel.call(%{}, %{
+             client_registry: %{
+               primary: "RuntimeFallback",
+               clients: [
+                 %{
+                   name: "BrokenGPT4oMini",
+                   provider: "openai",
+                   options: %{model: "gpt-4o-mini", api_key: "invalid"}
+                 },
+                 %{
+                   name: "RuntimeFallback",
+                   provider: "fallback",
+                   strategy: ["BrokenGPT4oMini", "GPT4"]
+                 }
+               ]
+             }
+           }) == {:ok, :GPT4oMini}
+  end
+
+  test "reject strategy clients referring to unknown clients" do
+    assert {:error,
+            %{
+              kind: :invalid_client_registry,
+              client: "RuntimeFallback",
+              option: "strategy",
+              message: "Strategy of client RuntimeFallback refers to unknown client Missing"
+            }} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               client_registry: %{
+                 clients: [
+                   %{name: "RuntimeFallback", provider: "fallback", strategy: ["Missing", "GPT4"]}
+                 ]
+               }
+             })
+  end
+
+  test "reuse a client registry resource across calls" do
+    assert {:ok, registry} =
+             BamlElixir.ClientRegistry.new(%{
+               clients: [
+                 %{
+                   name: "RuntimeGPT4oMini",
+                   provider: "openai",
+                   options: %{model: "gpt-4o-mini", api_key: System.get_env("OPENAI_API_KEY")}
+                 }
+               ]
+             })
+
+    for _ <- 1..2 do
+      assert BamlElixirTest.WhichModel.call(%{}, %{
+               client_registry: registry,
+               llm_client: "RuntimeGPT4oMini"
+             }) == {:ok, :GPT4oMini}
+    end
+  end
+
+  test "validate client registries up front" do
+    assert {:error,
+            %{
+              kind: :invalid_client_registry,
+              client: "BadClient",
+              option: "temperature",
+              message: "Option temperature of client BadClient must be a number"
+            }} =
+             BamlElixir.ClientRegistry.new(%{
+               clients: [%{name: "BadClient", provider: "openai", options: %{temperature: "hot"}}]
+             })
+
+    assert {:error, %{kind: :invalid_client_registry, client: "BadProvider"}} =
+             BamlElixir.ClientRegistry.new(%{
+               clients: [%{name: "BadProvider", provider: "not-a-provider"}]
+             })
+  end
+
+  test "retry with a runtime-defined retry policy" do
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:error, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               collectors: [collector],
+               client_registry: %{
+                 primary: "BrokenGPT4oMini",
+                 retry_policies: [
+                   %{name: "TwoRetries", max_retries: 2, strategy: "constant_delay", delay_ms: 10}
+                 ],
+                 clients: [
+                   %{
+                     name: "BrokenGPT4oMini",
+                     provider: "openai",
+                     retry_policy: "TwoRetries",
+                     options: %{model: "gpt-4o-mini", api_key: "invalid"}
+                   }
+                 ]
+               }
+             })
+
+    assert length(BamlElixir.Collector.last_function_log(collector)["calls"]) == 3
+  end
+
+  test "retry with a retry policy declared on a shared runtime" do
+    retry_policy = %{name: "TwoRetries", max_retries: 2, strategy: "constant_delay", delay_ms: 10}
+    {:ok, runtime} = BamlElixir.Runtime.new("test/baml_src", retry_policies: [retry_policy])
+    collector = BamlElixir.Collector.new("test-collector")
+
+    client_registry = %{
+      primary: "BrokenGPT4oMini",
+      clients: [
+        %{
+          name: "BrokenGPT4oMini",
+          provider: "openai",
+          retry_policy: "TwoRetries",
+          options: %{model: "gpt-4o-mini", api_key: "invalid"}
+        }
+      ]
+    }
+
+    assert {:error, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               collectors: [collector],
+               client_registry: client_registry
+             })
+
+    assert length(BamlElixir.Collector.last_function_log(collector)["calls"]) == 3
+
+    assert {:error, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               collectors: [collector],
+               client_registry: client_registry,
+               client_options: %{"BrokenGPT4oMini" => %{retry_policy: nil}}
+             })
+
+    assert length(BamlElixir.Collector.last_function_log(collector)["calls"]) == 1
+
+    assert {:error, %{kind: :invalid_client_registry, option: "retry_policies"}} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               client_registry: Map.put(client_registry, :retry_policies, [retry_policy])
+             })
+  end
+
+  test "override client options for a single call" do
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:ok, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               collectors: [collector],
+               client_options: %{"GPT4" => %{model: "gpt-4o", temperature: 0.0}}
+             })
+
+    request_body =
+      BamlElixir.Collector.last_function_log(collector)["calls"]
+      |> Enum.at(0)
+      |> Map.get("request")
+      |> Map.get("body")
+      |> Jason.decode!()
+
+    assert request_body["model"] == "gpt-4o"
+    assert request_body["temperature"] == 0.0
+  end
+
+  test "override options of clients of any provider" do
+    # Bedrock fails without AWS credentials, and the fallback moves on to GPT4
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               llm_client: "BedrockFallback",
+               client_options: %{
+                 "Bedrock" => %{inference_configuration: %{max_tokens: 100}},
+                 "GPT4" => %{temperature: 0.0}
+               }
+             })
+  end
+
+  test "add HTTP headers to the requests of a single call" do
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:ok, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               collectors: [collector],
+               headers: %{"x-tenant-id" => "acme"}
+             })
+
+    request_headers =
+      BamlElixir.Collector.last_function_log(collector)["calls"]
+      |> Enum.at(0)
+      |> get_in(["request", "headers"])
+
+    assert request_headers["x-tenant-id"] == "acme"
+
+    # Clients from the BAML source files that a fallback leads to get them too
+    assert {:ok, _} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               collectors: [collector],
+               headers: %{"x-tenant-id" => "acme"},
+               client_registry: %{
+                 primary: "RuntimeFallback",
+                 clients: [
+                   %{
+                     name: "BrokenGPT4oMini",
+                     provider: "openai",
+                     options: %{model: "gpt-4o-mini", api_key: "invalid"}
+                   },
+                   %{
+                     name: "RuntimeFallback",
+                     provider: "fallback",
+                     strategy: ["BrokenGPT4oMini", "GPT4"]
+                   }
+                 ]
+               }
+             })
+
+    calls = BamlElixir.Collector.last_function_log(collector)["calls"]
+    assert Enum.map(calls, & &1["client_name"]) == ["BrokenGPT4oMini", "GPT4"]
+    assert Enum.all?(calls, &(get_in(&1, ["request", "headers", "x-tenant-id"]) == "acme"))
+  end
+
+  test "add HTTP headers to calls whose strategy leads to clients of other providers" do
+    collector = BamlElixir.Collector.new("test-collector")
+
+    # BedrockFallback tries Bedrock, which takes no headers, before GPT4
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               llm_client: "BedrockFallback",
+               collectors: [collector],
+               headers: %{"x-tenant-id" => "acme"}
+             })
+
+    calls = BamlElixir.Collector.last_function_log(collector)["calls"]
+    gpt4_call = Enum.find(calls, &(&1["client_name"] == "GPT4"))
+    assert get_in(gpt4_call, ["request", "headers", "x-tenant-id"]) == "acme"
+  end
+
+  test "limit requests per client across calls sharing a runtime" do
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        limits: %{"GPT4" => %{requests_per_minute: 1, queue_timeout_ms: 100}}
+      )
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: runtime, llm_client: "GPT4"})
+
+    assert {:error, %{kind: :queue_timeout, client: "GPT4"}} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: runtime, llm_client: "GPT4"})
+  end
+
+  test "limit every request of a call, including retries" do
+    retry_policy = %{name: "TwoRetries", max_retries: 2, strategy: "constant_delay", delay_ms: 10}
+
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        retry_policies: [retry_policy],
+        limits: %{"Fake" => %{requests_per_minute: 2, queue_timeout_ms: 100}}
+      )
+
+    client_registry = %{
+      primary: "Fake",
+      clients: [
+        %{
+          name: "Fake",
+          provider: "mock",
+          retry_policy: "TwoRetries",
+          response: %{error: "Overloaded"}
+        }
+      ]
+    }
+
+    assert {:error,
+            "All clients failed: Fake: Overloaded; Fake: Overloaded; " <>
+              "Fake: Timed out waiting for its limits"} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               client_registry: client_registry
+             })
+  end
+
+  test "collectors log each request of a limited call as a function log of its own" do
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        limits: %{"GPT4" => %{max_concurrency: 4}}
+      )
+
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               collectors: [collector],
+               client_registry: %{
+                 primary: "RuntimeFallback",
+                 clients: [
+                   %{
+                     name: "BrokenGPT4oMini",
+                     provider: "openai",
+                     options: %{model: "gpt-4o-mini", api_key: "invalid"}
+                   },
+                   %{
+                     name: "RuntimeFallback",
+                     provider: "fallback",
+                     strategy: ["BrokenGPT4oMini", "GPT4"]
+                   }
+                 ]
+               }
+             })
+
+    # The failed request to BrokenGPT4oMini is logged in a function log before this one
+    assert [%{"client_name" => "GPT4"}] =
+             BamlElixir.Collector.last_function_log(collector)["calls"]
+  end
+
+  test "time out and cancel a call" do
+    client_registry = %{
+      primary: "InProcess",
+      retry_policies: [
+        %{name: "OneRetry", max_retries: 1, strategy: "constant_delay", delay_ms: 10}
+      ],
+      clients: [
+        %{
+          name: "InProcess",
+          provider: "elixir",
+          handler: self(),
+          retry_policy: "OneRetry",
+          options: %{model: "fake-model"}
+        }
+      ]
+    }
+
+    assert {:error, :timeout} =
+             BamlElixirTest.WhichModel.call(%{}, %{client_registry: client_registry, timeout: 100})
+
+    # The request in flight still gets its answer, but the cancelled call doesn't retry it
+    assert_receive {:baml_elixir_request, reply, %{client: "InProcess"}}
+    BamlElixir.Runtime.reply(reply, {:error, "Overloaded"})
+    refute_receive {:baml_elixir_request, _, _}, 200
+    refute_received {_ref, {:error, _}}
+  end
+
+  test "open the circuit of a failing client and skip it in fallbacks" do
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        circuit_breaker: %{failure_threshold: 1, open_ms: 60_000}
+      )
+
+    client_registry = %{
+      clients: [
+        %{
+          name: "BrokenGPT4oMini",
+          provider: "openai",
+          options: %{model: "gpt-4o-mini", api_key: "invalid"}
+        },
+        %{name: "RuntimeFallback", provider: "fallback", strategy: ["BrokenGPT4oMini", "GPT4"]}
+      ]
+    }
+
+    opts = %{runtime: runtime, client_registry: client_registry}
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, Map.put(opts, :llm_client, "RuntimeFallback"))
+
+    assert %{"BrokenGPT4oMini" => %{state: :open}, "GPT4" => %{state: :closed}} =
+             BamlElixir.Runtime.circuit_breakers(runtime)
+
+    assert {:error, %{kind: :circuit_open, client: "BrokenGPT4oMini"}} =
+             BamlElixirTest.WhichModel.call(%{}, Map.put(opts, :llm_client, "BrokenGPT4oMini"))
+
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(
+               %{},
+               Map.merge(opts, %{llm_client: "RuntimeFallback", collectors: [collector]})
+             )
+
+    assert [%{"client_name" => "GPT4"}] =
+             BamlElixir.Collector.last_function_log(collector)["calls"]
+  end
+
+  test "collectors log each request of a call with a circuit breaker as a function log of its own" do
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        circuit_breaker: %{failure_threshold: 5, open_ms: 60_000}
+      )
+
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: runtime,
+               collectors: [collector],
+               client_registry: %{
+                 primary: "RuntimeFallback",
+                 clients: [
+                   %{
+                     name: "BrokenGPT4oMini",
+                     provider: "openai",
+                     options: %{model: "gpt-4o-mini", api_key: "invalid"}
+                   },
+                   %{
+                     name: "RuntimeFallback",
+                     provider: "fallback",
+                     strategy: ["BrokenGPT4oMini", "GPT4"]
+                   }
+                 ]
+               }
+             })
+
+    assert %{"BrokenGPT4oMini" => %{state: :closed, consecutive_failures: 1}} =
+             BamlElixir.Runtime.circuit_breakers(runtime)
+
+    assert [%{"client_name" => "GPT4"}] =
+             BamlElixir.Collector.last_function_log(collector)["calls"]
+
+    assert BamlElixir.Collector.usage(collector)["input_tokens"] > 0
+  end
+
+  test "fail fast when every client of nested strategies has an open circuit" do
+    {:ok, runtime} =
+      BamlElixir.Runtime.new("test/baml_src",
+        circuit_breaker: %{failure_threshold: 1, error_rate: 1, open_ms: 60_000}
+      )
+
+    client_registry = %{
+      primary: "Outer",
+      clients: [
+        %{name: "FakeA", provider: "mock", response: %{error: "Boom"}},
+        %{name: "FakeB", provider: "mock", response: %{error: "Boom"}},
+        %{name: "Inner", provider: "fallback", strategy: ["FakeA"]},
+        %{name: "Outer", provider: "fallback", strategy: ["Inner", "FakeB"]}
+      ]
+    }
+
+    opts = %{runtime: runtime, client_registry: client_registry}
+
+    assert {:error, "All clients failed: FakeA: Boom; FakeB: Boom"} =
+             BamlElixirTest.WhichModel.call(%{}, opts)
+
+    assert {:error, %{kind: :circuit_open, client: "Outer", retry_after_ms: retry_after_ms}} =
+             BamlElixirTest.WhichModel.call(%{}, opts)
+
+    assert retry_after_ms > 0
+
+    assert {:error, "Circuit breaker option error_rate must be a number between 0 and 1"} =
+             BamlElixir.Runtime.new("test/baml_src", circuit_breaker: %{error_rate: 2})
+  end
+
+  test "resolve secrets through a process" do
+    test_pid = self()
+
+    resolver =
+      spawn_link(fn ->
+        Stream.repeatedly(fn ->
+          receive do
+            {:baml_elixir_secret, reply, name} ->
+              send(test_pid, {:secret_requested, name})
+
+              case System.get_env(name) do
+                nil -> BamlElixir.Runtime.reply(reply, :error)
+                value -> BamlElixir.Runtime.reply(reply, {:ok, value})
+              end
+          end
+        end)
+        |> Stream.run()
+      end)
+
+    {:ok, runtime} = BamlElixir.Runtime.new("test/baml_src", secrets: %{resolver: resolver})
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: runtime, llm_client: "GPT4"})
+
+    assert_received {:secret_requested, "OPENAI_API_KEY"}
+    # Only the clients the call may use are resolved
+    refute_received {:secret_requested, "ANTHROPIC_API_KEY"}
+
+    # Answers are cached
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: runtime, llm_client: "GPT4"})
+
+    refute_received {:secret_requested, "OPENAI_API_KEY"}
+
+    # Including shorthand clients, whose provider reads its API key by default
+    BamlElixirTest.WhichModel.call(%{}, %{
+      runtime: runtime,
+      llm_client: "anthropic/claude-3-5-haiku-latest"
+    })
+
+    assert_received {:secret_requested, "ANTHROPIC_API_KEY"}
+  end
+
+  test "hand the requests of a client to an Elixir process" do
+    test_pid = self()
+
+    handler =
+      spawn_link(fn ->
+        Stream.repeatedly(fn ->
+          receive do
+            {:baml_elixir_request, reply, %{stream: false} = request} ->
+              send(test_pid, {:request, request})
+              body = Jason.encode!(%{choices: [%{message: %{content: "GPT4oMini"}}]})
+              BamlElixir.Runtime.reply(reply, {:ok, %{status: 200, body: body}})
+
+            {:baml_elixir_request, reply, %{stream: true}} ->
+              for content <- ["GPT4", "oMini"] do
+                event = Jason.encode!(%{choices: [%{delta: %{content: content}}]})
+                BamlElixir.Runtime.reply(reply, {:chunk, "data: #{event}\n\n"})
+              end
+
+              BamlElixir.Runtime.reply(reply, :done)
+          end
+        end)
+        |> Stream.run()
+      end)
+
+    opts = %{
+      client_registry: %{
+        primary: "InProcess",
+        clients: [
+          %{
+            name: "InProcess",
+            provider: "elixir",
+            handler: handler,
+            options: %{model: "fake-model"}
+          }
+        ]
+      }
+    }
+
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, opts)
+
+    assert_received {:request, %{client: "InProcess", method: "POST", body: body}}
+    assert Jason.decode!(body)["model"] == "fake-model"
+
+    BamlElixirTest.WhichModel.stream(%{}, fn result -> send(test_pid, result) end, opts)
+    messages = wait_for_all_messages()
+
+    assert List.last(messages) == {:done, :GPT4oMini}
+  end
+
+  test "answer with canned responses from a mock client" do
+    assert {:ok, registry} =
+             BamlElixir.ClientRegistry.new(%{
+               primary: "Fake",
+               clients: [
+                 %{
+                   name: "Fake",
+                   provider: "mock",
+                   latency_ms: 10,
+                   responses: %{
+                     "WhichModel" => ["GPT4oMini", %{chunks: ["Deep", "Seek", "R1"]}]
+                   },
+                   response: %{error: "No more responses"}
+                 }
+               ]
+             })
+
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, %{client_registry: registry})
+
+    test_pid = self()
+
+    BamlElixirTest.WhichModel.stream(%{}, fn result -> send(test_pid, result) end, %{
+      client_registry: registry
+    })
+
+    messages = wait_for_all_messages()
+    assert List.last(messages) == {:done, :DeepSeekR1}
+
+    assert {:error, "All clients failed: Fake: No more responses"} =
+             BamlElixirTest.WhichModel.call(%{}, %{client_registry: registry})
+
+    assert {:error, %{kind: :invalid_client_registry, client: "Fake", option: "responses"}} =
+             BamlElixir.ClientRegistry.new(%{
+               clients: [%{name: "Fake", provider: "mock", responses: "GPT4oMini"}]
+             })
+  end
+
+  test "retry and rotate mock clients like other clients" do
+    client_registry = %{
+      primary: "Flaky",
+      retry_policies: [
+        %{name: "OneRetry", max_retries: 1, strategy: "constant_delay", delay_ms: 10}
+      ],
+      clients: [
+        %{
+          name: "Flaky",
+          provider: "mock",
+          retry_policy: "OneRetry",
+          responses: %{"WhichModel" => [%{error: "Overloaded"}, "GPT4oMini"]}
+        }
+      ]
+    }
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{client_registry: client_registry})
+
+    # Round-robin clients rotate across the calls sharing a runtime
+    {:ok, runtime} = BamlElixir.Runtime.new("test/baml_src")
+
+    client_registry = %{
+      primary: "MockRotation",
+      clients: [
+        %{name: "FakeMini", provider: "mock", response: "GPT4oMini"},
+        %{name: "FakeDeepSeek", provider: "mock", response: "DeepSeekR1"},
+        %{name: "MockRotation", provider: "round-robin", strategy: ["FakeMini", "FakeDeepSeek"]}
+      ]
+    }
+
+    opts = %{runtime: runtime, client_registry: client_registry}
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, opts)
+    assert {:ok, :DeepSeekR1} = BamlElixirTest.WhichModel.call(%{}, opts)
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, opts)
+  end
+
+  @tag :tmp_dir
+  test "record responses to a cassette and replay them", %{tmp_dir: tmp_dir} do
+    test_pid = self()
+    path = Path.join(tmp_dir, "which_model.jsonl")
+
+    {:ok, recorder} =
+      BamlElixir.Runtime.new("test/baml_src", cassette: %{path: path, mode: :record})
+
+    mock_registry = %{
+      primary: "Fake",
+      clients: [%{name: "Fake", provider: "mock", responses: %{"WhichModel" => ["DeepSeekR1"]}}]
+    }
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: recorder, llm_client: "GPT4"})
+
+    assert {:ok, :DeepSeekR1} =
+             BamlElixirTest.WhichModel.call(%{}, %{
+               runtime: recorder,
+               client_registry: mock_registry
+             })
+
+    # Each request is a line of its own, with the response as it was received when it's
+    # known and the text BAML parsed
+    interactions = path |> File.read!() |> String.split("\n", trim: true)
+
+    assert [
+             %{
+               "client" => "GPT4",
+               "function" => "WhichModel",
+               "response" => %{"status" => 200, "body" => body},
+               "text" => text
+             },
+             %{"client" => "Fake", "response" => nil, "text" => "DeepSeekR1"}
+           ] = Enum.map(interactions, &Jason.decode!/1)
+
+    assert %{"choices" => [%{"message" => %{"content" => ^text}}]} = Jason.decode!(body)
+
+    # Replayed without calling GPT4 or the mock, once per recorded response
+    {:ok, player} =
+      BamlElixir.Runtime.new("test/baml_src",
+        cassette: %{path: path, mode: :replay, match_on: [:function, :client]}
+      )
+
+    assert {:ok, :GPT4oMini} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: player, llm_client: "GPT4"})
+
+    assert {:error, "All clients failed: GPT4: The 1 responses recorded in " <> _} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: player, llm_client: "GPT4"})
+
+    assert {:ok, :DeepSeekR1} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: player, client_registry: mock_registry})
+
+    assert {:error, "All clients failed: DeepSeekR1: No response recorded in " <> _} =
+             BamlElixirTest.WhichModel.call(%{}, %{runtime: player, llm_client: "DeepSeekR1"})
+
+    # With repeat, the last response matching is replayed to any further requests
+    {:ok, repeating_player} =
+      BamlElixir.Runtime.new("test/baml_src",
+        cassette: %{path: path, mode: :replay, match_on: [:function, :client], repeat: true}
+      )
+
+    opts = %{runtime: repeating_player, llm_client: "GPT4"}
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, opts)
+    assert {:ok, :GPT4oMini} = BamlElixirTest.WhichModel.call(%{}, opts)
+    BamlElixirTest.WhichModel.stream(%{}, fn result -> send(test_pid, result) end, opts)
+    assert List.last(wait_for_all_messages()) == {:done, :GPT4oMini}
+
+    collector = BamlElixir.Collector.new("test-collector")
+
+    assert {:error, "Collectors only log the requests BAML sends" <> _} =
+             BamlElixirTest.WhichModel.call(%{}, Map.put(opts, :collectors, [collector]))
+  end
+
   test "Error when parsing the output of a function" do
     assert {:error, "Failed to coerce value" <> _} = BamlElixirTest.DummyOutputFunction.call(%{})
   end